@@ -2,16 +2,16 @@ use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::marker::PhantomData;
 use core::ptr::NonNull;
-use acpi::{AcpiHandler, AcpiTables, InterruptModel, PhysicalMapping};
-use acpi::platform::interrupt::InterruptSourceOverride;
-use limine::{LimineBootInfoRequest, LimineKernelAddressRequest, LimineMemmapRequest, LimineTerminalRequest, LimineTerminalResponse, LimineRsdpRequest, LimineSmpRequest};
+use acpi::{AcpiHandler, AcpiTables, HpetInfo, InterruptModel, PhysicalMapping};
+use acpi::platform::Processor;
+use acpi::platform::interrupt::Apic;
+use limine::{LimineBootInfoRequest, LimineKernelAddressRequest, LimineMemmapRequest, LimineTerminalRequest, LimineTerminalResponse, LimineRsdpRequest, LimineSmpRequest, LimineHhdmRequest};
 use crate::{debug, println};
 
 #[cfg(feature = "f_multiboot2")]
 use multiboot2::{load, MemoryMapTag, BootInformation};
-use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, Translate};
-use x86_64::VirtAddr;
-use crate::memory::{BootInfoFrameAllocator, FRAME_ALLOC, MEM_MAPPER, PageSize, read_phys_memory32, VIRT_MEM_OFFSET};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory::phys_to_virt;
 use crate::serial::terminal::ST;
 
 pub static BOOTLOADER_INFO: LimineBootInfoRequest = LimineBootInfoRequest::new(0);
@@ -20,32 +20,24 @@ pub static MEM_MAP: LimineMemmapRequest = LimineMemmapRequest::new(0);
 pub static RSDP_REQUEST: LimineRsdpRequest = LimineRsdpRequest::new(0);
 pub static KERNEL_ADDRESS: LimineKernelAddressRequest = LimineKernelAddressRequest::new(0);
 pub static SMP_REQUEST: LimineSmpRequest = LimineSmpRequest::new(0);
+pub static HHDM_REQUEST: LimineHhdmRequest = LimineHhdmRequest::new(0);
 
 #[derive(Clone)]
 struct Handler;
 impl AcpiHandler for Handler {
-    unsafe fn map_physical_region<T>(&self, physical_address: usize, size: usize) -> PhysicalMapping<Self, T> { // todo! check if size is too big
-        // only get lower 32 bits of physical address
-        let physical_address = physical_address as u32;
-        debug!("mapping physical region: {:x} - {:x}", physical_address, physical_address + size as u32);
-        let _ = read_phys_memory32(physical_address as u32) as usize;
+    unsafe fn map_physical_region<T>(&self, physical_address: usize, size: usize) -> PhysicalMapping<Self, T> {
+        // acpi tables live in memory the bootloader already direct maps, so just hand out the alias
+        let virtual_address = phys_to_virt(PhysAddr::new(physical_address as u64));
+        debug!("mapping physical region: {:x} - {:x} at {:x}", physical_address, physical_address + size, virtual_address.as_u64());
         PhysicalMapping::new(
-            physical_address as usize,
-            NonNull::new_unchecked(physical_address as *mut T),
+            physical_address,
+            NonNull::new_unchecked(virtual_address.as_mut_ptr::<T>()),
             size, size,
             Self)
     }
 
-    fn unmap_physical_region<T>(region: &PhysicalMapping<Self, T>) {
-        // get page
-        let page: Page<PageSize> = Page::containing_address(VirtAddr::new(region.physical_start() as u64));
-        // unmap page
-        let res = unsafe { MEM_MAPPER.lock().as_mut().unwrap().unmap(page) };
-        // it isn't *that* important if we don't unmap successfully at the moment, so just write a warning if we fail
-
-        if let Err(e) = res {
-            debug!("(THIS IS NORMAL) failed to unmap physical region: {:?}", e);
-        }
+    fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {
+        // nothing to do, the direct map is permanent
     }
 }
 
@@ -71,18 +63,44 @@ impl core::fmt::Write for LimineWriter {
     }
 }
 
-pub fn get_ioapic_info() -> (u32, Vec<InterruptSourceOverride>) {
+pub fn get_hhdm_offset() -> VirtAddr {
+    let hhdm = HHDM_REQUEST.get_response().get().expect("no higher half direct map");
+    VirtAddr::new(hhdm.offset)
+}
+
+fn acpi_tables() -> AcpiTables<Handler> {
     let rsdp = RSDP_REQUEST.get_response().get().unwrap();
     let rsdp_ptr = rsdp.address.get().unwrap() as *const u8;
-    let tables = unsafe { AcpiTables::from_rsdp(Handler, rsdp_ptr as usize).unwrap() };
+    // limine hands us a direct map address, but acpi wants the physical one
+    let hhdm_offset = get_hhdm_offset().as_u64();
+    let rsdp_phys = if rsdp_ptr as u64 >= hhdm_offset { rsdp_ptr as u64 - hhdm_offset } else { rsdp_ptr as u64 };
+    unsafe { AcpiTables::from_rsdp(Handler, rsdp_phys as usize).unwrap() }
+}
+
+/// parses the madt, returning the interrupt controller layout and every processor (bsp first)
+pub fn get_apic_info() -> (Apic, Vec<Processor>) {
+    let tables = acpi_tables();
     let platform_info = tables.platform_info().expect("no platform info");
-    let interrupt_model = platform_info.interrupt_model;
-    let apic = match interrupt_model {
+    let apic = match platform_info.interrupt_model {
         InterruptModel::Apic(apic) => apic,
         _ => panic!("unsupported interrupt model"),
     };
-    let ioapic = apic.io_apics.first().expect("no ioapic");
-    let address = ioapic.address;
-    let overrides = apic.interrupt_source_overrides;
-    (address, overrides)
-}
\ No newline at end of file
+    if apic.io_apics.is_empty() {
+        panic!("no ioapic");
+    }
+    let processors = match platform_info.processor_info {
+        Some(info) => {
+            let mut processors = Vec::with_capacity(info.application_processors.len() + 1);
+            processors.push(info.boot_processor);
+            processors.extend(info.application_processors);
+            processors
+        }
+        None => Vec::new(),
+    };
+    (apic, processors)
+}
+
+/// the hpet table, if the firmware has one
+pub fn get_hpet_info() -> Option<HpetInfo> {
+    HpetInfo::new(&acpi_tables()).ok()
+}
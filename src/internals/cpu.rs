@@ -1,15 +1,26 @@
 use alloc::vec::Vec;
 use core::arch::asm;
-use acpi::platform::interrupt::InterruptSourceOverride;
+use core::arch::x86_64::__cpuid;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use acpi::platform::{Processor, ProcessorState};
+use acpi::platform::interrupt::{LocalInterruptLine, NmiLine, NmiProcessor};
 use lazy_static::lazy_static;
-use spin::Mutex;
-use x2apic::ioapic::{IoApic, IrqFlags, IrqMode, RedirectionTableEntry};
-use x2apic::lapic::{LocalApic, LocalApicBuilder, xapic_base};
+use x2apic::lapic::{LocalApic, LocalApicBuilder, TimerDivide, TimerMode, xapic_base};
 use x86_64::PhysAddr;
+use x86_64::registers::model_specific::Msr;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::structures::idt::InterruptStackFrame;
 use x86_64::structures::paging::PhysFrame;
-use crate::{debug, print, println};
-use crate::memory::{BootInfoFrameAllocator, read_phys_memory32, write_phys_memory32};
+use crate::{debug, lock_class, println};
+use crate::internals::percpu::{self, SwapGsGuard};
+use crate::internals::softirq::{self, Tasklet};
+use crate::memory::mmio::ioremap;
+use crate::security::random;
+use crate::sync::SpinLock;
+use crate::task;
+use crate::task::workqueue::queue_work;
+use crate::time;
 use crate::serial::{command, read};
 use crate::serial::simplifiers::handle_scancode;
 
@@ -20,27 +31,139 @@ pub const APIC_INTERRUPT_OFFSET: usize = 32;
 pub const TIMER_IRQ: usize = 0 + APIC_INTERRUPT_OFFSET;
 pub const ERROR_IRQ: usize = 1 + APIC_INTERRUPT_OFFSET;
 pub const SPURIOUS_IRQ: usize = 2 + APIC_INTERRUPT_OFFSET;
+pub const CALL_FUNCTION_IRQ: usize = 3 + APIC_INTERRUPT_OFFSET;
+pub const RESCHEDULE_IRQ: usize = 4 + APIC_INTERRUPT_OFFSET;
 
-pub const IOAPIC_IRQ_OFFSET: usize = 42;
-pub const FALLBACK_KEYBOARD_IRQ: usize = 1 + IOAPIC_IRQ_OFFSET;
+/// most cpus we keep per-cpu state for
+pub const MAX_CPUS: usize = 64;
 
+// lapic registers, as (x2apic msr, offset from the xapic base)
+const LAPIC_ID: (u32, u64) = (0x802, 0x020);
+const LAPIC_EOI: (u32, u64) = (0x80b, 0x0b0);
+const LVT_LINT0: (u32, u64) = (0x835, 0x350);
+const LVT_LINT1: (u32, u64) = (0x836, 0x360);
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+
+// where the local apic registers ended up in xapic mode, needed for the bits x2apic doesn't wrap
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+static X2APIC: AtomicBool = AtomicBool::new(false);
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_BSP: u64 = 1 << 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ApicMode {
+    /// registers are memory mapped, apic ids are 8 bits
+    XApic,
+    /// registers are msrs, apic ids are 32 bits
+    X2Apic,
+}
+
+/// one entry per processor the madt lists
+#[derive(Clone, Copy, Debug)]
+pub struct CpuInfo {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    pub state: ProcessorState,
+    pub is_bsp: bool,
+}
+
+// how many scancodes can wait to be handled before the keyboard interrupt drops new ones
+const SCANCODE_RING_SIZE: usize = 64;
+
+// scancodes the keyboard interrupt took but hasn't had handled yet. the interrupt is the only one
+// pushing and `handle_scancodes` the only one popping, so the ring needs no lock and pushing
+// doesn't allocate
+struct ScancodeRing {
+    scancodes: [AtomicU8; SCANCODE_RING_SIZE],
+    // counts of scancodes popped and pushed so far, the difference is what's waiting
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+impl ScancodeRing {
+    const fn new() -> ScancodeRing {
+        const EMPTY: AtomicU8 = AtomicU8::new(0);
+        ScancodeRing {
+            scancodes: [EMPTY; SCANCODE_RING_SIZE],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    // false if it's full
+    fn push(&self, scancode: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == SCANCODE_RING_SIZE {
+            return false;
+        }
+        self.scancodes[tail % SCANCODE_RING_SIZE].store(scancode, Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let scancode = self.scancodes[head % SCANCODE_RING_SIZE].load(Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(scancode)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+}
+
+static SCANCODES: ScancodeRing = ScancodeRing::new();
+// hands the scancodes from the interrupt over to the system work queue
+static SCANCODE_TASKLET: Tasklet = Tasklet::new(queue_scancode_work);
+// `handle_scancodes` is queued or running, so there's no need to queue it again
+static SCANCODE_WORK_QUEUED: AtomicBool = AtomicBool::new(false);
 
 lazy_static!{
-    static ref LAPIC: Mutex<LocalApic> = {
-        // we need to get the xapic region
+    static ref CPUS: SpinLock<Vec<CpuInfo>> = SpinLock::named("CPUS", lock_class!(), Vec::new());
+    // lint pins the madt wants as nmi, as (processor uid or all, lvt register), so aps can program theirs too
+    static ref LINT_NMIS: SpinLock<Vec<(Option<u32>, (u32, u64))>> = SpinLock::named("LINT_NMIS", lock_class!(), Vec::new());
+    // every cpu finds its own xapic at the same address, so one mapping does for all of them
+    static ref XAPIC_BASE: u64 = {
         let phys_addr = unsafe { xapic_base() };
-
-        let mut lapic = LocalApicBuilder::new()
-            .timer_vector(TIMER_IRQ as usize)
-            .spurious_vector(SPURIOUS_IRQ as usize)
-            .error_vector(ERROR_IRQ as usize)
-            .set_xapic_base(phys_addr)
-            .build()
-            .unwrap_or_else(|e| panic!("failed to build local apic: {}", e));
-        Mutex::new(lapic)
+        ioremap(PhysAddr::new(phys_addr), 4096)
+            .unwrap_or_else(|e| panic!("failed to map local apic: {:?}", e))
+            .leak()
+            .as_u64()
     };
 }
 
+fn build_lapic() -> LocalApic {
+    let mut builder = LocalApicBuilder::new();
+    builder
+        .timer_vector(TIMER_IRQ as usize)
+        .spurious_vector(SPURIOUS_IRQ as usize)
+        .error_vector(ERROR_IRQ as usize);
+    // the x2apic crate switches to x2apic mode by itself whenever cpuid advertises it,
+    // so only bother mapping the mmio registers when we're going to use them
+    if check_x2apic_compat() {
+        X2APIC.store(true, Ordering::SeqCst);
+    } else {
+        LAPIC_BASE.store(*XAPIC_BASE, Ordering::SeqCst);
+        builder.set_xapic_base(*XAPIC_BASE);
+    }
+    builder
+        .build()
+        .unwrap_or_else(|e| panic!("failed to build local apic: {}", e))
+}
+
+// runs `f` on this cpu's local apic, building the handle the first time
+pub(crate) fn with_lapic<R>(f: impl FnOnce(&mut LocalApic) -> R) -> R {
+    without_interrupts(|| {
+        let mut lapic = percpu::this_cpu().lapic.lock();
+        f(lapic.get_or_insert_with(build_lapic))
+    })
+}
+
 pub fn check_apic_compat() -> bool {
     unsafe {
         let mut eax: u32;
@@ -54,6 +177,35 @@ pub fn check_apic_compat() -> bool {
     }
 }
 
+pub fn check_x2apic_compat() -> bool {
+    unsafe { __cpuid(1) }.ecx & (1 << 21) != 0
+}
+
+/// which mode the local apic runs in, only meaningful once it's been set up
+pub fn apic_mode() -> ApicMode {
+    if X2APIC.load(Ordering::SeqCst) {
+        ApicMode::X2Apic
+    } else {
+        ApicMode::XApic
+    }
+}
+
+// reads a lapic register without going through the lock
+unsafe fn read_lapic_register(register: (u32, u64)) -> u32 {
+    match apic_mode() {
+        ApicMode::X2Apic => Msr::new(register.0).read() as u32,
+        ApicMode::XApic => ptr::read_volatile((LAPIC_BASE.load(Ordering::SeqCst) + register.1) as *const u32),
+    }
+}
+
+// writes a lapic register the x2apic crate has no accessor for
+unsafe fn write_lapic_register(register: (u32, u64), value: u32) {
+    match apic_mode() {
+        ApicMode::X2Apic => Msr::new(register.0).write(value as u64),
+        ApicMode::XApic => ptr::write_volatile((LAPIC_BASE.load(Ordering::SeqCst) + register.1) as *mut u32, value),
+    }
+}
+
 pub fn tell_pic8259a_to_f_off() {
     unsafe {
         asm!("cli");
@@ -71,37 +223,172 @@ pub fn tell_pic8259a_to_f_off() {
     }
 }
 
+/// enables the local apic of the cpu we're running on. every cpu has to call this once, after
+/// it has its per-cpu block and before anything else here
 pub fn enable_apic() {
-    unsafe {
-        LAPIC.lock().enable();
+    with_lapic(|lapic| unsafe { lapic.enable() });
+    percpu::this_cpu().apic_id.store(lapic_id(), Ordering::SeqCst);
+}
+
+/// is this the cpu the bootloader started us on. reads an msr, `PerCpu::is_bsp` has it cached
+pub fn is_bsp() -> bool {
+    unsafe { Msr::new(IA32_APIC_BASE).read() & APIC_BASE_BSP != 0 }
+}
+
+/// number of the cpu we're running on, 0 to `online_cpus() - 1` in the order they came up
+pub fn cpu_index() -> usize {
+    percpu::this_cpu().index
+}
+
+/// cpus that have started setting themselves up so far
+pub fn online_cpus() -> usize {
+    percpu::count()
+}
+
+/// remembers the lint pins the madt marks as nmi and routes them on this cpu
+pub fn setup_lint_nmis(nmi_lines: &[NmiLine]) {
+    let mut lint_nmis = LINT_NMIS.lock();
+    lint_nmis.clear();
+    for nmi in nmi_lines {
+        let uid = match nmi.processor {
+            NmiProcessor::All => None,
+            NmiProcessor::ProcessorUid(target) => Some(target),
+        };
+        let register = match nmi.line {
+            LocalInterruptLine::Lint0 => LVT_LINT0,
+            LocalInterruptLine::Lint1 => LVT_LINT1,
+        };
+        debug!("lapic nmi on {:?} for {:?}", nmi.line, uid);
+        lint_nmis.push((uid, register));
+    }
+    drop(lint_nmis);
+    program_lint_nmis();
+}
+
+/// routes this cpu's nmi lint pins. has to run after `enable_apic`, which masks both pins
+pub fn program_lint_nmis() {
+    let uid = current_processor_uid();
+    for (target, register) in LINT_NMIS.lock().iter() {
+        if target.is_some() && *target != uid {
+            continue;
+        }
+        // nmis are always edge triggered, and the acpi crate drops the polarity so assume active high
+        unsafe {
+            write_lapic_register(*register, LVT_DELIVERY_NMI);
+        }
     }
 }
 
+/// fills the cpu table from the processors listed in the madt
+pub fn init_topology(processors: &[Processor]) {
+    let mut cpus = CPUS.lock();
+    cpus.clear();
+    for processor in processors {
+        cpus.push(CpuInfo {
+            processor_uid: processor.processor_uid,
+            apic_id: processor.local_apic_id,
+            state: processor.state,
+            is_bsp: !processor.is_ap,
+        });
+    }
+}
+
+pub fn cpu_count() -> usize {
+    CPUS.lock().iter().filter(|cpu| cpu.state != ProcessorState::Disabled).count()
+}
+
+pub fn cpus() -> Vec<CpuInfo> {
+    CPUS.lock().clone()
+}
+
+/// acpi processor uid of the cpu we're running on, if it's in the table
+pub fn current_processor_uid() -> Option<u32> {
+    let apic_id = lapic_id();
+    CPUS.lock().iter().find(|cpu| cpu.apic_id == apic_id).map(|cpu| cpu.processor_uid)
+}
+
+pub fn print_topology() {
+    for cpu in CPUS.lock().iter() {
+        println!("cpu: uid {} apic id {} {:?}{}", cpu.processor_uid, cpu.apic_id, cpu.state,
+            if cpu.is_bsp { " (bsp)" } else { "" });
+    }
+}
+
+fn program_lapic_timer(mode: TimerMode, initial: u32) {
+    with_lapic(|lapic| unsafe {
+        lapic.disable_timer();
+        lapic.set_timer_divide(TimerDivide::Div16);
+        lapic.set_timer_mode(mode);
+        lapic.set_timer_initial(initial);
+        lapic.enable_timer();
+    });
+}
+
+/// fires the timer interrupt every `initial` lapic timer ticks
+pub fn lapic_timer_periodic(initial: u32) {
+    program_lapic_timer(TimerMode::Periodic, initial);
+}
+
+/// fires the timer interrupt once, `initial` lapic timer ticks from now
+pub fn lapic_timer_oneshot(initial: u32) {
+    program_lapic_timer(TimerMode::OneShot, initial);
+}
+
+pub fn lapic_timer_current() -> u32 {
+    with_lapic(|lapic| unsafe { lapic.timer_current() })
+}
+
+pub fn lapic_timer_stop() {
+    with_lapic(|lapic| unsafe {
+        lapic.disable_timer();
+        lapic.set_timer_initial(0);
+    });
+}
+
 pub extern "x86-interrupt" fn timer(stack_frame: InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(&stack_frame);
+    random::add_interrupt_timing(TIMER_IRQ as u8);
+    time::tick();
     end_of_interupt();
+    softirq::run_pending();
+    // may switch threads, we get back here (and iretq into the old thread) once it's rescheduled
+    task::timer_tick();
 }
 
 pub extern "x86-interrupt" fn error(stack_frame: InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(&stack_frame);
     println!("error interrupt");
     end_of_interupt();
 }
 
 pub extern "x86-interrupt" fn spurious(stack_frame: InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(&stack_frame);
     println!("spurious interrupt");
     end_of_interupt();
 }
 
-fn end_of_interupt() {
+// straight to the register, every cpu sends these and they mustn't wait on each other
+pub fn end_of_interupt() {
     unsafe {
-        LAPIC.lock().end_of_interrupt();
+        write_lapic_register(LAPIC_EOI, 0);
+    }
+}
+
+/// apic id of the cpu we're running on, only valid once this cpu has run `enable_apic`
+pub fn lapic_id() -> u32 {
+    let id = unsafe { read_lapic_register(LAPIC_ID) };
+    match apic_mode() {
+        ApicMode::X2Apic => id,
+        // the xapic id register keeps the id in the top byte
+        ApicMode::XApic => id >> 24,
     }
 }
 
 // todo! in the future this will be removed, it is only for testing basic apic functionality
-pub extern "x86-interrupt" fn keyboard_irq(stack_frame: InterruptStackFrame) {
+// the top half: grab the scancode and ack the controller, decoding and echoing it happens on a worker
+pub fn keyboard_irq(_vector: u8) {
     let scancode = read(0x60);
-
-    handle_scancode(scancode);
+    random::add_entropy(scancode as u64);
 
     // reset keyboard controller
     let mut a = read(0x61);
@@ -110,29 +397,28 @@ pub extern "x86-interrupt" fn keyboard_irq(stack_frame: InterruptStackFrame) {
     a &= 0x7f;
     command(0x61, a);
 
-    end_of_interupt();
+    if !SCANCODES.push(scancode) {
+        // nothing got to the ones before it, better to lose this one than block in here
+        return;
+    }
+    SCANCODE_TASKLET.schedule();
 }
 
-// todo! we should abstract this away
-pub fn setup_ioapic(ioapicaddr: u32, isos: Vec<InterruptSourceOverride>) {
-    let mut ioapic = unsafe {
-        IoApic::new(ioapicaddr as u64)
-    };
-    // setup keyboard interrupt
-    unsafe {
-        // FIXME! only for testing that this works, abstract ASAP!
-        let gsi_keyboard = isos.iter().find(|iso| iso.isa_source == 1)
-            .map(|iso| iso.global_system_interrupt).unwrap_or(1);
-        // init with irq offset
-        ioapic.init(IOAPIC_IRQ_OFFSET as u8);
-        let mut entry = RedirectionTableEntry::default();
-        entry.set_mode(IrqMode::Fixed);
-        entry.set_flags(IrqFlags::LEVEL_TRIGGERED | IrqFlags::LOW_ACTIVE | IrqFlags::MASKED);
-        entry.set_dest(0);
-        entry.set_vector(gsi_keyboard as u8 + IOAPIC_IRQ_OFFSET as u8);
-        ioapic.set_table_entry(1, entry);
-
-        ioapic.enable_irq(1);
-    }
-
-}
\ No newline at end of file
+fn queue_scancode_work() {
+    if !SCANCODE_WORK_QUEUED.swap(true, Ordering::SeqCst) {
+        queue_work(handle_scancodes);
+    }
+}
+
+fn handle_scancodes() {
+    loop {
+        while let Some(scancode) = SCANCODES.pop() {
+            handle_scancode(scancode);
+        }
+        SCANCODE_WORK_QUEUED.store(false, Ordering::SeqCst);
+        // one that came in before the flag went down didn't queue us again, so take it ourselves
+        if SCANCODES.is_empty() || SCANCODE_WORK_QUEUED.swap(true, Ordering::SeqCst) {
+            return;
+        }
+    }
+}
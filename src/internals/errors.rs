@@ -7,6 +7,7 @@ use x86_64::structures::idt::PageFaultErrorCode;
 use crate::{InterruptStackFrame, font, println, print};
 use crate::internals::WhyDoTheyCallItOvenWhenYouOfInTheColdFoodOfOutHotEatTheFood::{COMMUNIST_RED, CUM_WHITE, Colour};
 use crate::serial::terminal::ST;
+use crate::memory::stack::guard_page_hit;
 
 pub extern "x86-interrupt" fn breakpoint_exception(stack_frame: InterruptStackFrame) {
     println!("---KERNEL WARNING UWU---");
@@ -17,6 +18,11 @@ pub extern "x86-interrupt" fn breakpoint_exception(stack_frame: InterruptStackFr
 pub extern "x86-interrupt" fn double_fault(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
     println!("---KERNEL FUCKY WUKKY UWU---");
     println!("double fault!");
+    let accessed = Cr2::read();
+    if let Some(stack) = guard_page_hit(accessed) {
+        println!("kernel stack overflow: hit the guard page of the stack at {:#x} - {:#x}", stack.start.as_u64(), stack.end().as_u64());
+    }
+    println!("last accessed address: {:?}", accessed);
     println!("stack frame: {:#?}", stack_frame);
     println!("error code: {}", error_code);
     loop {}
@@ -25,6 +31,9 @@ pub extern "x86-interrupt" fn double_fault(stack_frame: InterruptStackFrame, err
 pub extern "x86-interrupt" fn page_fault(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
     println!("---KERNEL FUCKY WUKKY UWU---");
     println!("page fault!");
+    if let Some(stack) = guard_page_hit(Cr2::read()) {
+        println!("kernel stack overflow: hit the guard page of the stack at {:#x} - {:#x}", stack.start.as_u64(), stack.end().as_u64());
+    }
     println!("accessed address: {:?}", Cr2::read());
     println!("error code: {:?}", error_code);
     println!("stack frame: {:#?}", stack_frame);
@@ -0,0 +1,87 @@
+use alloc::boxed::Box;
+use lazy_static::lazy_static;
+use x86_64::instructions::tables::load_tss;
+use x86_64::registers::segmentation::{CS, Segment, SS};
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+use crate::internals::percpu;
+use crate::memory::stack::{alloc_stack, KERNEL_STACK_SIZE};
+
+const EARLY_STACK_SIZE: usize = 4096 * 5;
+// one per ist index the idt uses (exceptions, irqs, double fault)
+const EARLY_STACK_COUNT: usize = crate::DOUBLE_FAULT_IST_INDEX as usize + 1;
+
+// the heap and vmalloc don't exist yet when the bsp first needs an idt, so it starts out on these
+static mut EARLY_STACKS: [[u8; EARLY_STACK_SIZE]; EARLY_STACK_COUNT] = [[0; EARLY_STACK_SIZE]; EARLY_STACK_COUNT];
+
+struct Selectors {
+    code: SegmentSelector,
+    data: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+lazy_static! {
+    static ref EARLY_TSS: TaskStateSegment = {
+        let mut tss = TaskStateSegment::new();
+        for i in 0..EARLY_STACK_COUNT {
+            let stack_start = VirtAddr::from_ptr(unsafe { core::ptr::addr_of!(EARLY_STACKS[i]) });
+            tss.interrupt_stack_table[i] = stack_start + EARLY_STACK_SIZE;
+        }
+        tss
+    };
+    static ref EARLY_GDT: (GlobalDescriptorTable, Selectors) = {
+        let mut gdt = GlobalDescriptorTable::new();
+        let code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let data = gdt.add_entry(Descriptor::kernel_data_segment());
+        let tss = gdt.add_entry(Descriptor::tss_segment(&EARLY_TSS));
+        (gdt, Selectors { code, data, tss })
+    };
+}
+
+/// loads a gdt, tss and the idt on static stacks, so faults during memory init get reported
+/// instead of triple faulting. bsp only, before anything else touches memory; `init_cpu` moves
+/// it onto proper stacks once vmalloc is up
+pub fn init_early() {
+    EARLY_GDT.0.load();
+    unsafe {
+        CS::set_reg(EARLY_GDT.1.code);
+        SS::set_reg(EARLY_GDT.1.data);
+        load_tss(EARLY_GDT.1.tss);
+    }
+    crate::IDT.load();
+}
+
+/// gives the cpu we're running on its own gdt and tss, with fresh ist and privilege stacks, and
+/// loads them. both live as long as the cpu does, so they're leaked. every cpu runs this once,
+/// after `percpu::init_cpu`
+pub fn init_cpu() {
+    // new() already points the io bitmap past the end of the tss, so there's no bitmap
+    let tss = Box::leak(Box::new(TaskStateSegment::new()));
+    // every ist and privilege stack gets its own guard page, so an overflow faults
+    // (and ends up on the double fault stack) instead of trampling memory
+    for i in 0..7 {
+        tss.interrupt_stack_table[i] = alloc_stack(KERNEL_STACK_SIZE)
+            .expect("failed to allocate ist stack")
+            .leak();
+    }
+    for i in 0..3 {
+        tss.privilege_stack_table[i] = alloc_stack(KERNEL_STACK_SIZE)
+            .expect("failed to allocate privilege stack")
+            .leak();
+    }
+    let tss: &'static TaskStateSegment = tss;
+    percpu::this_cpu().set_tss(tss);
+
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    let kcs = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kds = gdt.add_entry(Descriptor::kernel_data_segment());
+    let tsss = gdt.add_entry(Descriptor::tss_segment(tss));
+    let gdt: &'static GlobalDescriptorTable = gdt;
+    gdt.load();
+    unsafe {
+        CS::set_reg(kcs);
+        SS::set_reg(kds);
+        load_tss(tsss);
+    }
+}
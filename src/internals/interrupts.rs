@@ -0,0 +1,286 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use acpi::platform::interrupt::{Apic, Polarity, TriggerMode};
+use lazy_static::lazy_static;
+use x2apic::ioapic::{IoApic, IrqFlags, IrqMode, RedirectionTableEntry};
+use x86_64::PhysAddr;
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::{debug, lock_class, println};
+use crate::internals::{cpu, softirq};
+use crate::internals::percpu::SwapGsGuard;
+use crate::memory::mmio::ioremap;
+use crate::security::random;
+use crate::sync::SpinLock;
+
+/// first vector handed out to drivers, everything below is exceptions and the fixed lapic vectors
+pub const DYNAMIC_IRQ_BASE: u8 = 48;
+/// last vector handed out to drivers. the fixed lapic vectors all sit below `DYNAMIC_IRQ_BASE`,
+/// the ones above this are simply left unused
+pub const DYNAMIC_IRQ_END: u8 = 0xef;
+
+/// what gets called when a requested interrupt fires, with the vector it fired on.
+/// runs in interrupt context, the eoi is sent after it returns
+pub type IrqHandler = fn(vector: u8);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IrqSource {
+    /// legacy isa irq, translated through the madt interrupt source overrides
+    Isa(u8),
+    /// global system interrupt, i.e. an ioapic input
+    Gsi(u32),
+}
+
+#[derive(Debug)]
+pub enum IrqError {
+    NoFreeVector,
+    NoIoApic,
+    GsiOutOfRange(u32),
+    GsiInUse(u32),
+}
+
+/// a requested interrupt line, pass it back to `free_irq` when done
+#[derive(Clone, Copy, Debug)]
+pub struct Irq {
+    pub gsi: u32,
+    pub vector: u8,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Override {
+    isa_source: u8,
+    gsi: u32,
+    flags: IrqFlags,
+}
+
+struct IoApicChip {
+    id: u8,
+    ioapic: IoApic,
+    // first gsi this ioapic is responsible for, its inputs cover gsi_base..=gsi_base + max_entry
+    gsi_base: u32,
+    max_entry: u8,
+}
+
+impl IoApicChip {
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi <= self.gsi_base + self.max_entry as u32
+    }
+}
+
+struct IrqState {
+    ioapics: Vec<IoApicChip>,
+    overrides: Vec<Override>,
+    // gsi -> vector, for every line someone requested (and the nmi sources)
+    routes: BTreeMap<u32, u8>,
+}
+
+impl IrqState {
+    /// the ioapic a gsi is wired to, along with the input number on that ioapic
+    fn chip_for(&mut self, gsi: u32) -> Option<(&mut IoApicChip, u8)> {
+        self.ioapics.iter_mut()
+            .find(|chip| chip.handles(gsi))
+            .map(|chip| {
+                let pin = (gsi - chip.gsi_base) as u8;
+                (chip, pin)
+            })
+    }
+}
+
+const NO_HANDLER: AtomicUsize = AtomicUsize::new(0);
+// fn pointers stored as usizes so dispatch never has to take a lock
+static HANDLERS: [AtomicUsize; 256] = [NO_HANDLER; 256];
+// vector the ioapics deliver nmi sources on, ignored by the cpu for nmi delivery but has to be set to something
+const NMI_VECTOR: u8 = 2;
+
+lazy_static! {
+    static ref VECTORS_USED: SpinLock<[bool; 256]> = SpinLock::named("VECTORS_USED", lock_class!(), [false; 256]);
+    static ref IRQS: SpinLock<Option<IrqState>> = SpinLock::named("IRQS", lock_class!(), None);
+}
+
+fn flags_from_madt(polarity: &Polarity, trigger_mode: &TriggerMode, default: IrqFlags) -> IrqFlags {
+    let mut flags = default;
+    match polarity {
+        Polarity::ActiveHigh => flags.remove(IrqFlags::LOW_ACTIVE),
+        Polarity::ActiveLow => flags.insert(IrqFlags::LOW_ACTIVE),
+        Polarity::SameAsBus => {}
+    }
+    match trigger_mode {
+        TriggerMode::Edge => flags.remove(IrqFlags::LEVEL_TRIGGERED),
+        TriggerMode::Level => flags.insert(IrqFlags::LEVEL_TRIGGERED),
+        TriggerMode::SameAsBus => {}
+    }
+    flags
+}
+
+// isa lines are edge triggered and active high unless the madt says otherwise
+const ISA_DEFAULT_FLAGS: IrqFlags = IrqFlags::empty();
+// anything else (pci and friends) is level triggered and active low
+const GSI_DEFAULT_FLAGS: IrqFlags = IrqFlags::from_bits_truncate(IrqFlags::LEVEL_TRIGGERED.bits() | IrqFlags::LOW_ACTIVE.bits());
+
+/// maps every ioapic in the madt, masks all their inputs, programs the nmi sources
+/// and remembers the overrides for later requests
+pub fn init_ioapics(apic: &Apic) {
+    let mut ioapics = Vec::new();
+    for info in apic.io_apics.iter() {
+        let ioapic_virt = ioremap(PhysAddr::new(info.address as u64), 4096)
+            .unwrap_or_else(|e| panic!("failed to map ioapic {}: {:?}", info.id, e))
+            .leak();
+        let mut ioapic = unsafe { IoApic::new(ioapic_virt.as_u64()) };
+        let max_entry = unsafe { ioapic.max_table_entry() };
+        for i in 0..=max_entry {
+            let mut entry = RedirectionTableEntry::default();
+            entry.set_flags(IrqFlags::MASKED);
+            unsafe { ioapic.set_table_entry(i, entry) };
+        }
+        debug!("ioapic {}: {:#x}, gsi {} - {}", info.id, info.address, info.global_system_interrupt_base,
+            info.global_system_interrupt_base + max_entry as u32);
+        ioapics.push(IoApicChip {
+            id: info.id,
+            ioapic,
+            gsi_base: info.global_system_interrupt_base,
+            max_entry,
+        });
+    }
+
+    let overrides = apic.interrupt_source_overrides.iter().map(|iso| {
+        let flags = flags_from_madt(&iso.polarity, &iso.trigger_mode, ISA_DEFAULT_FLAGS);
+        debug!("interrupt source override: isa {} -> gsi {} ({:?})", iso.isa_source, iso.global_system_interrupt, flags);
+        Override {
+            isa_source: iso.isa_source,
+            gsi: iso.global_system_interrupt,
+            flags,
+        }
+    }).collect();
+
+    let mut state = IrqState {
+        ioapics,
+        overrides,
+        routes: BTreeMap::new(),
+    };
+
+    // nmi sources can't be used by devices, so they get programmed once and stay reserved
+    let dest = cpu::lapic_id() as u8;
+    for nmi in apic.nmi_sources.iter() {
+        let gsi = nmi.global_system_interrupt;
+        let default = if gsi < 16 { ISA_DEFAULT_FLAGS } else { GSI_DEFAULT_FLAGS };
+        let flags = flags_from_madt(&nmi.polarity, &nmi.trigger_mode, default);
+        match state.chip_for(gsi) {
+            Some((chip, pin)) => {
+                let mut entry = RedirectionTableEntry::default();
+                entry.set_mode(IrqMode::NonMaskable);
+                entry.set_flags(flags);
+                entry.set_dest(dest);
+                entry.set_vector(NMI_VECTOR);
+                unsafe { chip.ioapic.set_table_entry(pin, entry) };
+                debug!("nmi source: gsi {} on ioapic {} pin {} ({:?})", gsi, chip.id, pin, flags);
+            }
+            None => println!("nmi source on gsi {} isn't handled by any ioapic", gsi),
+        }
+        state.routes.insert(gsi, NMI_VECTOR);
+    }
+
+    IRQS.lock().replace(state);
+}
+
+/// reserves a free vector in the dynamic range
+pub fn allocate_vector() -> Option<u8> {
+    let mut used = VECTORS_USED.lock();
+    let vector = (DYNAMIC_IRQ_BASE..=DYNAMIC_IRQ_END).find(|v| !used[*v as usize])?;
+    used[vector as usize] = true;
+    Some(vector)
+}
+
+pub fn free_vector(vector: u8) {
+    unregister_handler(vector);
+    VECTORS_USED.lock()[vector as usize] = false;
+}
+
+/// points an already allocated vector at `handler`, for interrupts that don't come through the ioapic
+pub fn register_handler(vector: u8, handler: IrqHandler) {
+    HANDLERS[vector as usize].store(handler as usize, Ordering::SeqCst);
+}
+
+pub fn unregister_handler(vector: u8) {
+    HANDLERS[vector as usize].store(0, Ordering::SeqCst);
+}
+
+/// works out which gsi a source ends up on and how the line has to be programmed
+fn resolve(state: &IrqState, source: IrqSource) -> (u32, IrqFlags) {
+    match source {
+        IrqSource::Isa(isa) => state.overrides.iter()
+            .find(|o| o.isa_source == isa)
+            .map(|o| (o.gsi, o.flags))
+            .unwrap_or((isa as u32, ISA_DEFAULT_FLAGS)),
+        IrqSource::Gsi(gsi) => state.overrides.iter()
+            .find(|o| o.gsi == gsi)
+            .map(|o| (o.gsi, o.flags))
+            // identity mapped isa lines without an override are still isa lines
+            .unwrap_or((gsi, if gsi < 16 { ISA_DEFAULT_FLAGS } else { GSI_DEFAULT_FLAGS })),
+    }
+}
+
+/// routes `source` to a freshly allocated vector on the current cpu and unmasks it
+pub fn request_irq(source: IrqSource, handler: IrqHandler) -> Result<Irq, IrqError> {
+    request_irq_inner(source, None, handler)
+}
+
+/// like `request_irq`, but for devices that know better than the madt how their line is wired
+/// (e.g. the hpet, which is edge triggered and active high on any gsi)
+pub fn request_irq_with_flags(source: IrqSource, flags: IrqFlags, handler: IrqHandler) -> Result<Irq, IrqError> {
+    request_irq_inner(source, Some(flags), handler)
+}
+
+fn request_irq_inner(source: IrqSource, flags: Option<IrqFlags>, handler: IrqHandler) -> Result<Irq, IrqError> {
+    let mut state = IRQS.lock();
+    let state = state.as_mut().ok_or(IrqError::NoIoApic)?;
+    let (gsi, madt_flags) = resolve(state, source);
+    let flags = flags.unwrap_or(madt_flags);
+    if state.routes.contains_key(&gsi) {
+        return Err(IrqError::GsiInUse(gsi));
+    }
+    let (chip, pin) = state.chip_for(gsi).ok_or(IrqError::GsiOutOfRange(gsi))?;
+
+    let vector = allocate_vector().ok_or(IrqError::NoFreeVector)?;
+    register_handler(vector, handler);
+
+    let mut entry = RedirectionTableEntry::default();
+    entry.set_mode(IrqMode::Fixed);
+    entry.set_flags(flags);
+    // ioapic destinations are only 8 bits, cpus with bigger x2apic ids would need interrupt remapping
+    entry.set_dest(cpu::lapic_id() as u8);
+    entry.set_vector(vector);
+    unsafe {
+        chip.ioapic.set_table_entry(pin, entry);
+    }
+    debug!("irq: {:?} -> gsi {} (ioapic {} pin {}) -> vector {} ({:?})", source, gsi, chip.id, pin, vector, flags);
+    state.routes.insert(gsi, vector);
+
+    Ok(Irq { gsi, vector })
+}
+
+/// masks the line again and gives the vector back
+pub fn free_irq(irq: Irq) {
+    if let Some(state) = IRQS.lock().as_mut() {
+        if let Some((chip, pin)) = state.chip_for(irq.gsi) {
+            unsafe { chip.ioapic.disable_irq(pin) };
+        }
+        state.routes.remove(&irq.gsi);
+    }
+    free_vector(irq.vector);
+}
+
+/// common entry point for every vector in the dynamic range, installed with `set_general_handler!`
+pub fn dispatch(stack_frame: InterruptStackFrame, vector: u8, _error_code: Option<u64>) {
+    let _gs = SwapGsGuard::new(&stack_frame);
+    random::add_interrupt_timing(vector);
+    let handler = HANDLERS[vector as usize].load(Ordering::SeqCst);
+    if handler != 0 {
+        let handler: IrqHandler = unsafe { mem::transmute(handler) };
+        handler(vector);
+    } else {
+        println!("unexpected interrupt on vector {}", vector);
+    }
+    cpu::end_of_interupt();
+    softirq::run_pending();
+}
@@ -0,0 +1,207 @@
+use alloc::collections::VecDeque;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x2apic::lapic::{IpiAllShorthand, LocalApic};
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::internals::cpu::{self, ApicMode, CALL_FUNCTION_IRQ, RESCHEDULE_IRQ};
+use crate::internals::percpu::{self, PerCpu, SwapGsGuard};
+use crate::lock_class;
+use crate::percpu;
+use crate::sync::SpinLock;
+use crate::task;
+
+// a function some cpu asked others to run. it lives on the sender's stack, which is fine because
+// the sender doesn't return until every target has run it and counted itself off `pending`
+struct CallData {
+    func: *const (dyn Fn() + Sync),
+    pending: AtomicUsize,
+}
+
+struct CallRef(*const CallData);
+
+// only ever points at a `CallData` whose sender is still waiting on it
+unsafe impl Send for CallRef {}
+
+percpu! {
+    // calls waiting for this cpu to get to them
+    static CALL_QUEUE: SpinLock<VecDeque<CallRef>> = SpinLock::named("CALL_QUEUE", lock_class!(), VecDeque::new());
+}
+
+// the icr wants the xapic id in the top byte of its upper half
+fn destination(apic_id: u32) -> u32 {
+    match cpu::apic_mode() {
+        ApicMode::X2Apic => apic_id,
+        ApicMode::XApic => apic_id << 24,
+    }
+}
+
+// sends through this cpu's lapic, after the last ipi has left. x2apic has no delivery status, it
+// always reads as idle
+fn send(f: impl FnOnce(&mut LocalApic)) {
+    cpu::with_lapic(|lapic| unsafe {
+        while lapic.get_ipi_delivery_status() {
+            core::hint::spin_loop();
+        }
+        f(lapic);
+    });
+}
+
+fn shorthand(including_self: bool) -> IpiAllShorthand {
+    if including_self {
+        IpiAllShorthand::AllIncludingSelf
+    } else {
+        IpiAllShorthand::AllExcludingSelf
+    }
+}
+
+/// whether `cpu` has its idt and lapic up, i.e. whether an ipi to it gets handled
+pub fn accepts_ipis(cpu: &PerCpu) -> bool {
+    cpu.apic_id.load(Ordering::SeqCst) != u32::MAX
+}
+
+/// interrupts cpu number `index` on `vector`
+pub fn send_ipi(index: usize, vector: u8) {
+    let apic_id = match percpu::cpu(index) {
+        Some(cpu) if accepts_ipis(cpu) => cpu.apic_id.load(Ordering::SeqCst),
+        _ => return,
+    };
+    send(|lapic| unsafe { lapic.send_ipi(vector, destination(apic_id)) });
+}
+
+/// interrupts every cpu on `vector`. this includes cpus limine found that we never started or that
+/// are still on their way up, so only for vectors every idt handles
+pub fn send_ipi_all(vector: u8, including_self: bool) {
+    send(|lapic| unsafe { lapic.send_ipi_all(vector, shorthand(including_self)) });
+}
+
+pub fn send_nmi(index: usize) {
+    let apic_id = match percpu::cpu(index) {
+        Some(cpu) if accepts_ipis(cpu) => cpu.apic_id.load(Ordering::SeqCst),
+        _ => return,
+    };
+    send(|lapic| unsafe { lapic.send_nmi(destination(apic_id)) });
+}
+
+/// nmis every cpu, for when the others have to stop no matter what they're doing (a panic)
+pub fn send_nmi_all(including_self: bool) {
+    send(|lapic| unsafe { lapic.send_nmi_all(shorthand(including_self)) });
+}
+
+/// resets the cpu with local apic id `apic_id` into wait-for-sipi. limine does the startup dance
+/// for the cpus it finds, this is for ones we have to start (or restart) ourselves
+pub unsafe fn send_init(apic_id: u32) {
+    send(|lapic| lapic.send_init_ipi(destination(apic_id)));
+}
+
+/// starts a cpu sitting in wait-for-sipi at real mode address `page << 12`
+pub unsafe fn send_sipi(apic_id: u32, page: u8) {
+    send(|lapic| lapic.send_sipi(page, destination(apic_id)));
+}
+
+/// gets cpu number `index` to look at its `need_resched` now instead of at its next tick
+pub fn send_reschedule(index: usize) {
+    send_ipi(index, RESCHEDULE_IRQ as u8);
+}
+
+// which cpus a call goes to
+#[derive(Clone, Copy)]
+enum Targets {
+    Others,
+    One(usize),
+    All,
+}
+
+impl Targets {
+    fn includes(&self, index: usize, this: usize) -> bool {
+        match self {
+            Targets::Others => index != this,
+            Targets::One(target) => index == *target,
+            Targets::All => true,
+        }
+    }
+}
+
+/// runs `f` on every other cpu and waits until they've all finished. `f` runs in interrupt context
+/// with interrupts off, so it must not block. never call this holding a spinlock, another cpu may
+/// be spinning on it with interrupts off and never get to `f`
+pub fn smp_call_function<F: Fn() + Sync>(f: F) {
+    call_on(Targets::Others, &f);
+}
+
+/// runs `f` on cpu number `index` and waits for it, running it here if that's this cpu
+pub fn smp_call_function_single<F: Fn() + Sync>(index: usize, f: F) {
+    call_on(Targets::One(index), &f);
+}
+
+/// runs `f` on every cpu, this one included
+pub fn on_each_cpu<F: Fn() + Sync>(f: F) {
+    call_on(Targets::All, &f);
+}
+
+fn call_on(targets: Targets, f: &(dyn Fn() + Sync)) {
+    // before the per-cpu blocks exist there's only the boot cpu, which becomes cpu 0
+    if percpu::count() == 0 {
+        if targets.includes(0, 0) {
+            f();
+        }
+        return;
+    }
+    // interrupts off keeps us on this cpu and its queue ours, but then a cpu waiting on us the same
+    // way would never get its answer, so we serve our own queue while we wait
+    interrupts::without_interrupts(|| {
+        let this = percpu::this_cpu();
+        let data = CallData {
+            // the lifetime goes, but we don't return until nobody can call it anymore
+            func: unsafe { mem::transmute::<*const (dyn Fn() + Sync + '_), *const (dyn Fn() + Sync)>(f) },
+            pending: AtomicUsize::new(0),
+        };
+        let mut run_here = false;
+        for cpu in percpu::cpus().filter(|cpu| targets.includes(cpu.index, this.index)) {
+            if cpu.index == this.index {
+                run_here = true;
+                continue;
+            }
+            if !accepts_ipis(cpu) {
+                continue;
+            }
+            data.pending.fetch_add(1, Ordering::SeqCst);
+            CALL_QUEUE.get_for(cpu.index).lock().push_back(CallRef(&data));
+            send_ipi(cpu.index, CALL_FUNCTION_IRQ as u8);
+        }
+        if run_here {
+            f();
+        }
+        while data.pending.load(Ordering::SeqCst) != 0 {
+            run_pending_calls();
+            core::hint::spin_loop();
+        }
+    });
+}
+
+// runs whatever other cpus queued for us, with interrupts off
+fn run_pending_calls() {
+    loop {
+        let call = CALL_QUEUE.get().lock().pop_front();
+        let data = match call {
+            Some(CallRef(data)) => unsafe { &*data },
+            None => return,
+        };
+        unsafe { (*data.func)() };
+        // the sender may free `data` as soon as this lands
+        data.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub extern "x86-interrupt" fn call_function(stack_frame: InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(&stack_frame);
+    run_pending_calls();
+    cpu::end_of_interupt();
+}
+
+pub extern "x86-interrupt" fn reschedule(stack_frame: InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(&stack_frame);
+    cpu::end_of_interupt();
+    // whoever sent it already set our need_resched
+    task::reschedule_interrupt();
+}
@@ -1,6 +1,11 @@
 pub mod errors;
 pub mod interrupts;
 pub mod cpu;
+pub mod gdt;
+pub mod ipi;
+pub mod percpu;
+pub mod smp;
+pub mod softirq;
 
 pub mod WhyDoTheyCallItOvenWhenYouOfInTheColdFoodOfOutHotEatTheFood {
 
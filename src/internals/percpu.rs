@@ -0,0 +1,209 @@
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x2apic::lapic::LocalApic;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+use crate::internals::cpu::{self, MAX_CPUS};
+
+/// stands in for a thread id while a cpu isn't running one
+pub const NO_THREAD: u64 = u64::MAX;
+
+/// everything a cpu keeps for itself. `GS_BASE` points at it while we're in the kernel
+#[repr(C)]
+pub struct PerCpu {
+    // points back at the block, so `this_cpu` gets there with one gs relative load
+    self_ptr: *const PerCpu,
+    /// this cpu's number, 0 to `count() - 1` in the order they came up
+    pub index: usize,
+    /// whether this is the cpu the bootloader started us on
+    pub is_bsp: bool,
+    /// local apic id, set by `cpu::enable_apic`
+    pub apic_id: AtomicU32,
+    // built on first use, see `cpu::with_lapic`
+    pub(crate) lapic: Mutex<Option<LocalApic>>,
+    tss: AtomicPtr<TaskStateSegment>,
+    /// id of the thread running here, `NO_THREAD` until the cpu joins the scheduler
+    pub current_thread: AtomicU64,
+    pub idle_thread: AtomicU64,
+    /// outstanding `preempt_disable` calls on this cpu
+    pub preempt_count: AtomicUsize,
+    /// the running thread should give up the cpu as soon as it's allowed to
+    pub need_resched: AtomicBool,
+    /// interrupt handlers we're nested in, saved and restored with the thread on a switch
+    pub irq_depth: AtomicUsize,
+    // spinlocks held here, interrupts only come back on when the last one goes. `irqs_saved` is
+    // whether they were on when the first one was taken
+    pub(crate) spinlock_depth: AtomicUsize,
+    pub(crate) irqs_saved: AtomicBool,
+}
+
+// the lapic handle is only ever touched by its own cpu, everything else is atomic
+unsafe impl Sync for PerCpu {}
+
+const NO_CPU: AtomicPtr<PerCpu> = AtomicPtr::new(ptr::null_mut());
+static CPU_BLOCKS: [AtomicPtr<PerCpu>; MAX_CPUS] = [NO_CPU; MAX_CPUS];
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+// set once the boot cpu has its block. every cpu that takes a lock after that has one too, since
+// aps get theirs from `smp::start_aps` and load it before doing anything else
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// gives the boot cpu its per-cpu block and points `GS_BASE` at it. runs once, before anything
+/// that wants `this_cpu`
+pub fn init_cpu() -> &'static PerCpu {
+    let block = enter_cpu(alloc_cpu());
+    READY.store(true, Ordering::SeqCst);
+    block
+}
+
+/// a fresh block for a cpu that hasn't started yet, to be loaded on it with `enter_cpu`
+pub fn alloc_cpu() -> &'static mut PerCpu {
+    Box::leak(Box::new(PerCpu {
+        self_ptr: ptr::null(),
+        index: usize::MAX,
+        is_bsp: false,
+        apic_id: AtomicU32::new(u32::MAX),
+        lapic: Mutex::new(None),
+        tss: AtomicPtr::new(ptr::null_mut()),
+        current_thread: AtomicU64::new(NO_THREAD),
+        idle_thread: AtomicU64::new(NO_THREAD),
+        preempt_count: AtomicUsize::new(0),
+        need_resched: AtomicBool::new(false),
+        irq_depth: AtomicUsize::new(0),
+        spinlock_depth: AtomicUsize::new(0),
+        irqs_saved: AtomicBool::new(false),
+    }))
+}
+
+/// numbers the cpu we're running on, makes `block` its per-cpu block and points `GS_BASE` at it.
+/// doesn't allocate or take locks, so an ap can run it first thing
+pub fn enter_cpu(block: &'static mut PerCpu) -> &'static PerCpu {
+    block.self_ptr = block as *const PerCpu;
+    block.is_bsp = cpu::is_bsp();
+    block.index = COUNT.fetch_add(1, Ordering::SeqCst);
+    let block: &'static PerCpu = block;
+    // no user mode yet, so there's no user gs for swapgs to trade with
+    KernelGsBase::write(VirtAddr::new(0));
+    GsBase::write(VirtAddr::from_ptr(block as *const PerCpu));
+    assert!(block.index < MAX_CPUS, "more than {} cpus", MAX_CPUS);
+    CPU_BLOCKS[block.index].store(block as *const PerCpu as *mut PerCpu, Ordering::SeqCst);
+    block
+}
+
+/// the per-cpu block of the cpu we're running on. a thread can move to another cpu whenever
+/// it's preemptible, so keep preemption or interrupts off while it matters which cpu this is
+pub fn this_cpu() -> &'static PerCpu {
+    unsafe {
+        let block: *const PerCpu;
+        asm!("mov {}, gs:[0]", out(reg) block, options(nostack, preserves_flags, readonly));
+        &*block
+    }
+}
+
+/// like `this_cpu`, but none instead of a fault while the boot cpu is still setting up
+pub fn try_this_cpu() -> Option<&'static PerCpu> {
+    if READY.load(Ordering::SeqCst) {
+        Some(this_cpu())
+    } else {
+        None
+    }
+}
+
+/// another cpu's block, by its index
+pub fn cpu(index: usize) -> Option<&'static PerCpu> {
+    let block = CPU_BLOCKS.get(index)?.load(Ordering::SeqCst);
+    unsafe { block.as_ref() }
+}
+
+/// every cpu that has run `enter_cpu`
+pub fn cpus() -> impl Iterator<Item = &'static PerCpu> {
+    (0..count()).filter_map(cpu)
+}
+
+pub fn count() -> usize {
+    COUNT.load(Ordering::SeqCst).min(MAX_CPUS)
+}
+
+impl PerCpu {
+    pub fn tss(&self) -> Option<&'static TaskStateSegment> {
+        unsafe { self.tss.load(Ordering::SeqCst).as_ref() }
+    }
+
+    pub(crate) fn set_tss(&self, tss: &'static TaskStateSegment) {
+        self.tss.store(tss as *const TaskStateSegment as *mut TaskStateSegment, Ordering::SeqCst);
+    }
+}
+
+/// whether we're in an interrupt handler (or the softirq tail of one) on this cpu
+pub fn in_interrupt() -> bool {
+    try_this_cpu().map_or(false, |cpu| cpu.irq_depth.load(Ordering::SeqCst) != 0)
+}
+
+/// swaps in the kernel gs for an interrupt that came from user mode, and back when dropped.
+/// also counts the handler for `in_interrupt`. create one first thing in any handler that uses
+/// per-cpu data
+pub struct SwapGsGuard(bool);
+
+impl SwapGsGuard {
+    pub fn new(stack_frame: &InterruptStackFrame) -> SwapGsGuard {
+        let from_user = stack_frame.code_segment & 3 != 0;
+        if from_user {
+            unsafe {
+                asm!("swapgs", options(nostack, preserves_flags));
+            }
+        }
+        this_cpu().irq_depth.fetch_add(1, Ordering::SeqCst);
+        SwapGsGuard(from_user)
+    }
+}
+
+impl Drop for SwapGsGuard {
+    fn drop(&mut self) {
+        this_cpu().irq_depth.fetch_sub(1, Ordering::SeqCst);
+        if self.0 {
+            unsafe {
+                asm!("swapgs", options(nostack, preserves_flags));
+            }
+        }
+    }
+}
+
+/// one value per cpu, declared with `percpu!`
+pub struct PerCpuVar<T> {
+    values: [T; MAX_CPUS],
+}
+
+impl<T> PerCpuVar<T> {
+    #[doc(hidden)]
+    pub const fn new(values: [T; MAX_CPUS]) -> PerCpuVar<T> {
+        PerCpuVar { values }
+    }
+
+    /// this cpu's copy, with the same caveat about moving cpus as `this_cpu`
+    pub fn get(&self) -> &T {
+        &self.values[this_cpu().index]
+    }
+
+    pub fn get_for(&self, index: usize) -> &T {
+        &self.values[index]
+    }
+}
+
+/// declares statics that every cpu gets its own copy of, reached through `PerCpuVar::get`.
+/// the initialiser has to be const, and the type should be an atomic or a lock since other cpus
+/// can look at their copies too
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])* $vis static $name: $crate::internals::percpu::PerCpuVar<$ty> = {
+                const INIT: $ty = $init;
+                $crate::internals::percpu::PerCpuVar::new([INIT; $crate::internals::cpu::MAX_CPUS])
+            };
+        )*
+    };
+}
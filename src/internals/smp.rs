@@ -0,0 +1,84 @@
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use limine::LimineSmpInfo;
+use x86_64::instructions::interrupts;
+use crate::{debug, println};
+use crate::boot::SMP_REQUEST;
+use crate::internals::{cpu, gdt, percpu};
+use crate::internals::percpu::PerCpu;
+use crate::memory::mmio;
+use crate::security::hardening;
+use crate::{task, time};
+
+// how long to give each ap to check in before giving up on it
+const AP_TIMEOUT_MS: u64 = 1000;
+
+// aps that made it all the way into the scheduler
+static APS_ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// starts every application processor limine found, one at a time, and waits for each to join
+/// the scheduler. needs `task::init` and `time::init` done. returns how many cpus are running
+pub fn start_aps() -> usize {
+    let mut response = SMP_REQUEST.get_response();
+    let response = match response.get_mut() {
+        Some(response) => response,
+        None => {
+            println!("smp: bootloader didn't start any other cpus");
+            return 1;
+        }
+    };
+    let bsp_lapic_id = response.bsp_lapic_id;
+    let mut tried = 0;
+    for info in response.cpus().iter_mut() {
+        if info.lapic_id == bsp_lapic_id {
+            continue;
+        }
+        if tried + 1 >= cpu::MAX_CPUS {
+            println!("smp: only using the first {} cpus", cpu::MAX_CPUS);
+            break;
+        }
+        debug!("smp: starting cpu with lapic id {}", info.lapic_id);
+        tried += 1;
+        // counted from what's online now, so one that never showed up doesn't hold the rest back
+        let online = APS_ONLINE.load(Ordering::SeqCst);
+        // allocated here since the ap needs it before it can safely take the heap lock
+        let block = percpu::alloc_cpu();
+        // the ap is spinning on goto_address, writing it is what sets it off
+        unsafe {
+            ptr::write_volatile(&mut info.extra_argument, block as *mut PerCpu as u64);
+            ptr::write_volatile(&mut info.goto_address, ap_entry);
+        }
+        // one at a time, so a cpu that hangs on the way up is easy to spot
+        let deadline = time::monotonic_ns() + AP_TIMEOUT_MS * 1_000_000;
+        while APS_ONLINE.load(Ordering::SeqCst) == online {
+            if time::monotonic_ns() > deadline {
+                println!("smp: cpu with lapic id {} didn't come up", info.lapic_id);
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    1 + APS_ONLINE.load(Ordering::SeqCst)
+}
+
+/// where application processors start, on a stack from limine with interrupts off and paging
+/// set up the same as the boot cpu. does per-cpu setup, then idles in the scheduler
+extern "C" fn ap_entry(info: *const LimineSmpInfo) -> ! {
+    // locks find their per-cpu state through gs, so that goes first
+    percpu::enter_cpu(unsafe { &mut *((*info).extra_argument as *mut PerCpu) });
+    // everything the boot cpu set in its own registers has to be set again here
+    mmio::init();
+    hardening::enable_cpu_protections(&hardening::detect_protections());
+    gdt::init_cpu();
+    crate::IDT.load();
+    cpu::enable_apic();
+    cpu::program_lint_nmis();
+    time::start_periodic_tick();
+    task::init_ap();
+
+    let lapic_id = unsafe { (*info).lapic_id };
+    debug!("smp: cpu {} (lapic id {}) online", cpu::cpu_index(), lapic_id);
+    APS_ONLINE.fetch_add(1, Ordering::SeqCst);
+    interrupts::enable();
+    task::idle();
+}
@@ -0,0 +1,153 @@
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+use crate::percpu;
+use crate::lock_class;
+use crate::sync::SpinLock;
+use crate::time;
+
+// how often the pending set may be refilled while we're draining it before we leave the rest for
+// the next interrupt, so a storm of raises can't keep a cpu in softirqs forever
+const MAX_RESTARTS: usize = 10;
+
+/// the bottom halves interrupt handlers can ask for. they run on the cpu that raised them, after the
+/// eoi, with interrupts on, and mustn't sleep
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SoftIrq {
+    /// expired `time::timer` callbacks
+    Timer = 0,
+    /// scheduled `Tasklet`s
+    Tasklet = 1,
+}
+
+impl SoftIrq {
+    const ALL: [SoftIrq; 2] = [SoftIrq::Timer, SoftIrq::Tasklet];
+
+    fn run(&self) {
+        match self {
+            SoftIrq::Timer => time::timer::run_expired_timers(),
+            SoftIrq::Tasklet => run_tasklets(),
+        }
+    }
+}
+
+percpu! {
+    // a bit per `SoftIrq` raised on this cpu
+    static PENDING: AtomicUsize = AtomicUsize::new(0);
+    static IN_SOFTIRQ: AtomicBool = AtomicBool::new(false);
+    static TASKLETS: SpinLock<TaskletList> = SpinLock::named("TASKLETS", lock_class!(), TaskletList::new());
+}
+
+/// marks `irq` pending on this cpu, it runs at the end of the interrupt we're in (or the next one)
+pub fn raise(irq: SoftIrq) {
+    interrupts::without_interrupts(|| {
+        PENDING.get().fetch_or(1 << irq as usize, Ordering::SeqCst);
+    });
+}
+
+/// runs whatever is pending on this cpu. interrupt handlers call it last, with interrupts still off
+/// and the eoi sent. only the outermost handler does the work, so nested ones return quickly
+pub fn run_pending() {
+    if PENDING.get().load(Ordering::SeqCst) == 0 || IN_SOFTIRQ.get().swap(true, Ordering::SeqCst) {
+        return;
+    }
+    for _ in 0..MAX_RESTARTS {
+        let pending = PENDING.get().swap(0, Ordering::SeqCst);
+        if pending == 0 {
+            break;
+        }
+        interrupts::enable();
+        for irq in SoftIrq::ALL.iter().filter(|irq| pending & (1 << **irq as usize) != 0) {
+            irq.run();
+        }
+        interrupts::disable();
+    }
+    IN_SOFTIRQ.get().store(false, Ordering::SeqCst);
+}
+
+/// whether this cpu is running softirqs right now
+pub fn in_softirq() -> bool {
+    interrupts::without_interrupts(|| IN_SOFTIRQ.get().load(Ordering::SeqCst))
+}
+
+/// a function an interrupt handler can schedule to run from the tasklet softirq. scheduling it
+/// again before it ran only runs it once, and it never runs on two cpus at the same time.
+/// scheduling doesn't allocate, tasklets are linked through themselves
+pub struct Tasklet {
+    func: fn(),
+    scheduled: AtomicBool,
+    running: AtomicBool,
+    // the next one in the list it's scheduled on, only touched by whoever has that list
+    next: AtomicPtr<Tasklet>,
+}
+
+// tasklets waiting to run on a cpu, oldest first
+struct TaskletList {
+    head: Option<&'static Tasklet>,
+    tail: Option<&'static Tasklet>,
+}
+
+impl TaskletList {
+    const fn new() -> TaskletList {
+        TaskletList { head: None, tail: None }
+    }
+
+    fn push(&mut self, tasklet: &'static Tasklet) {
+        tasklet.next.store(ptr::null_mut(), Ordering::SeqCst);
+        match self.tail {
+            Some(tail) => tail.next.store(tasklet as *const Tasklet as *mut Tasklet, Ordering::SeqCst),
+            None => self.head = Some(tasklet),
+        }
+        self.tail = Some(tasklet);
+    }
+
+    fn pop(&mut self) -> Option<&'static Tasklet> {
+        let tasklet = self.head?;
+        self.head = unsafe { tasklet.next.load(Ordering::SeqCst).as_ref() };
+        if self.head.is_none() {
+            self.tail = None;
+        }
+        Some(tasklet)
+    }
+}
+
+impl Tasklet {
+    pub const fn new(func: fn()) -> Tasklet {
+        Tasklet {
+            func,
+            scheduled: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// queues it on this cpu, unless it's queued already
+    pub fn schedule(&'static self) {
+        if self.scheduled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        interrupts::without_interrupts(|| {
+            TASKLETS.get().lock().push(self);
+            raise(SoftIrq::Tasklet);
+        });
+    }
+}
+
+fn run_tasklets() {
+    let mut tasklets = interrupts::without_interrupts(|| core::mem::replace(&mut *TASKLETS.get().lock(), TaskletList::new()));
+    // each one is popped before it runs, running it can schedule it again and relink it
+    while let Some(tasklet) = tasklets.pop() {
+        if tasklet.running.swap(true, Ordering::SeqCst) {
+            // busy on another cpu, try again on our next pass
+            interrupts::without_interrupts(|| {
+                TASKLETS.get().lock().push(tasklet);
+                raise(SoftIrq::Tasklet);
+            });
+            continue;
+        }
+        // cleared first, so it can schedule itself again
+        tasklet.scheduled.store(false, Ordering::SeqCst);
+        (tasklet.func)();
+        tasklet.running.store(false, Ordering::SeqCst);
+    }
+}
@@ -5,6 +5,8 @@
 #![feature(const_mut_refs)]
 #![feature(alloc_error_handler)]
 #![feature(const_slice_from_raw_parts_mut)]
+#![feature(naked_functions)]
+#![feature(asm_sym)]
 #![no_std]
 #![no_main]
 
@@ -17,14 +19,10 @@ use core::arch::asm;
 use lazy_static::lazy_static;
 use core::panic::PanicInfo;
 use limine::{LimineBootInfoRequest, LimineMemmapRequest, LimineTerminalRequest};
-use spin::Mutex;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
-use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor};
-use x86_64::structures::tss::TaskStateSegment;
-use x86_64::{PhysAddr, set_general_handler, VirtAddr};
-use x86_64::registers::segmentation::{CS, Segment, SS};
+use x86_64::set_general_handler;
 use x86_64::structures::paging::Translate;
-use crate::boot::{get_ioapic_info, KERNEL_ADDRESS};
+use crate::boot::{get_apic_info, KERNEL_ADDRESS};
 use crate::internals::WhyDoTheyCallItOvenWhenYouOfInTheColdFoodOfOutHotEatTheFood::*;
 use crate::memory::{FRAME_ALLOC, MEM_MAPPER};
 use crate::serial::terminal::ST;
@@ -35,26 +33,37 @@ mod internals;
 mod security;
 mod boot;
 mod memory;
+mod time;
+mod task;
+mod sync;
 mod macros;
 
+const EXCEPTION_IST_INDEX: u16 = 0;
+const IRQ_IST_INDEX: u16 = 1;
+const DOUBLE_FAULT_IST_INDEX: u16 = 2;
+
 lazy_static! {
     //pub static ref KERN_INFO: Mutex<Option<KernelInfo>> = Mutex::new(None);
-    static ref GDT: Mutex<GlobalDescriptorTable> = {
-        let mut gdt = GlobalDescriptorTable::new();
-        Mutex::new(gdt)
-    };
-    static ref IDT: InterruptDescriptorTable = {
+    pub(crate) static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         unsafe {
             use internals::errors::unhandled;
             set_general_handler!(&mut idt, unhandled);
-            idt.breakpoint.set_handler_fn(internals::errors::breakpoint_exception).set_stack_index(0);
-            idt.double_fault.set_handler_fn(internals::errors::double_fault).set_stack_index(0);
-            idt.page_fault.set_handler_fn(internals::errors::page_fault).set_stack_index(0);
-            idt[internals::cpu::TIMER_IRQ].set_handler_fn(internals::cpu::timer).set_stack_index(1);
-            idt[internals::cpu::ERROR_IRQ].set_handler_fn(internals::cpu::error).set_stack_index(1);
-            idt[internals::cpu::SPURIOUS_IRQ].set_handler_fn(internals::cpu::spurious).set_stack_index(1);
-            idt[internals::cpu::FALLBACK_KEYBOARD_IRQ].set_handler_fn(internals::cpu::keyboard_irq).set_stack_index(1);
+            idt.breakpoint.set_handler_fn(internals::errors::breakpoint_exception).set_stack_index(EXCEPTION_IST_INDEX);
+            // the double fault gets a stack nothing else uses, so it still works when another ist stack overflowed
+            idt.double_fault.set_handler_fn(internals::errors::double_fault).set_stack_index(DOUBLE_FAULT_IST_INDEX);
+            idt.page_fault.set_handler_fn(internals::errors::page_fault).set_stack_index(EXCEPTION_IST_INDEX);
+            // no ist for the timer: its softirq tail turns interrupts back on, and a nested interrupt
+            // on the same ist stack would start over at the top and overwrite this one
+            idt[internals::cpu::TIMER_IRQ].set_handler_fn(internals::cpu::timer);
+            idt[internals::cpu::ERROR_IRQ].set_handler_fn(internals::cpu::error).set_stack_index(IRQ_IST_INDEX);
+            idt[internals::cpu::SPURIOUS_IRQ].set_handler_fn(internals::cpu::spurious).set_stack_index(IRQ_IST_INDEX);
+            idt[internals::cpu::CALL_FUNCTION_IRQ].set_handler_fn(internals::ipi::call_function).set_stack_index(IRQ_IST_INDEX);
+            // may switch threads, so no ist for the same reason as the timer
+            idt[internals::cpu::RESCHEDULE_IRQ].set_handler_fn(internals::ipi::reschedule);
+            // everything handed out by request_irq goes through the same dispatcher
+            use internals::interrupts::dispatch;
+            set_general_handler!(&mut idt, dispatch, internals::interrupts::DYNAMIC_IRQ_BASE..=internals::interrupts::DYNAMIC_IRQ_END);
         }
         idt
     };
@@ -65,11 +74,18 @@ const RAINBOW : [Colour; 6] = [Colour{r:255,g:0,b:0}, Colour{r:255,g:127,b:0}, C
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    memory::allocator::oom_report(layout);
     panic!("allocation error: {:?}", layout)
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // whoever panicked may have been in the middle of printing
+    unsafe {
+        ST.writer.force_unlock();
+        ST.writer.lock().port.force_unlock();
+        ST.port.force_unlock();
+    }
     println!("---KERNEL FUCKY WUKKY UWU (panic)---");
     if let Some(s) = info.payload().downcast_ref::<&str>() {
         println!("panic payload: {s:?}")
@@ -91,6 +107,9 @@ fn panic(info: &PanicInfo) -> ! {
 
 #[no_mangle]
 pub extern "C" fn kernel_main() -> ! {
+    security::random::init();
+    // replace the placeholder stack canary before anything that checks it gets to return
+    security::stack_smashing_protection::init_stack_guard(security::stack_smashing_protection::random_canary());
     debug!("entry point");
 
     // initialise serial
@@ -110,125 +129,6 @@ pub extern "C" fn kernel_main() -> ! {
 
     // temporarily disable interrupts
     x86_64::instructions::interrupts::disable();
-    println!("debug: setup GDT");
-    // load TSS
-    static mut tss: TaskStateSegment = TaskStateSegment::new();
-    {
-        unsafe {
-            {
-                tss.interrupt_stack_table[0] = {
-                    const STACK_SIZE: usize = 4096 * 5;
-                    static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-                    let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-                    let stack_end = stack_start + STACK_SIZE;
-                    stack_end
-                };
-                tss.interrupt_stack_table[1] = {
-                    const STACK_SIZE: usize = 4096 * 5;
-                    static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-                    let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-                    let stack_end = stack_start + STACK_SIZE;
-                    stack_end
-                };
-                tss.interrupt_stack_table[2] = {
-                    const STACK_SIZE: usize = 4096 * 5;
-                    static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-                    let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-                    let stack_end = stack_start + STACK_SIZE;
-                    stack_end
-                };
-                tss.interrupt_stack_table[3] = {
-                    const STACK_SIZE: usize = 4096 * 5;
-                    static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-                    let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-                    let stack_end = stack_start + STACK_SIZE;
-                    stack_end
-                };
-                tss.interrupt_stack_table[4] = {
-                    const STACK_SIZE: usize = 4096 * 5;
-                    static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-                    let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-                    let stack_end = stack_start + STACK_SIZE;
-                    stack_end
-                };
-                tss.interrupt_stack_table[5] = {
-                    const STACK_SIZE: usize = 4096 * 5;
-                    static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-                    let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-                    let stack_end = stack_start + STACK_SIZE;
-                    stack_end
-                };
-                tss.interrupt_stack_table[6] = {
-                    const STACK_SIZE: usize = 4096 * 5;
-                    static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-                    let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-                    let stack_end = stack_start + STACK_SIZE;
-                    stack_end
-                };
-            }
-            {
-                tss.privilege_stack_table[0] = {
-                    const STACK_SIZE: usize = 4096 * 5;
-                    static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-                    let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-                    let stack_end = stack_start + STACK_SIZE;
-                    stack_end
-                };
-                tss.privilege_stack_table[1] = {
-                    const STACK_SIZE: usize = 4096 * 5;
-                    static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-                    let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-                    let stack_end = stack_start + STACK_SIZE;
-                    stack_end
-                };
-                tss.privilege_stack_table[2] = {
-                    const STACK_SIZE: usize = 4096 * 5;
-                    static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-                    let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-                    let stack_end = stack_start + STACK_SIZE;
-                    stack_end
-                };
-            }
-            // set word at offset 102 to 0x68 and last two bytes of the tss to 0xffff
-            // this is a hack to make the tss valid
-            let tss_ptr = &tss as *const TaskStateSegment as *mut u8;
-            unsafe {
-                *tss_ptr.add(102) = 0x68;
-                *tss_ptr.add(104) = 0xff;
-                *tss_ptr.add(105) = 0xff;
-            }
-        }
-        let kcs = GDT.lock().add_entry(Descriptor::kernel_code_segment());
-        let kds = GDT.lock().add_entry(Descriptor::kernel_data_segment());
-        let tsss = unsafe { GDT.lock().add_entry(Descriptor::tss_segment(&tss)) };
-        // load GDT
-        unsafe {
-            GDT.lock().load_unsafe();
-        }
-        println!("debug: GDT loaded");
-        // set code segment to kernel code segment
-        unsafe {
-            CS::set_reg(kcs);
-        }
-        println!("debug: CS set");
-        // set data segment to kernel data segment
-        unsafe {
-            SS::set_reg(kds);
-        }
-        println!("debug: SS set");
-        // load TSS
-        unsafe {
-            x86_64::instructions::tables::load_tss(tsss);
-        }
-        println!("debug: TSS loaded");
-
-        // load IDT
-        IDT.load();
-        println!("debug: IDT loaded");
-        // enable interrupts
-        x86_64::instructions::interrupts::enable();
-    }
-
 
     println!();
     println!();
@@ -236,6 +136,10 @@ pub extern "C" fn kernel_main() -> ! {
     println!("welcome to wukkOS!");
     println!("(c) 2022 Real Microsoft, LLC");
 
+    // exceptions need somewhere to go before memory init can fault
+    internals::gdt::init_early();
+    println!("debug: early GDT and IDT loaded");
+
     // memory stuff
     {
         print!("initialising mapper...");
@@ -243,15 +147,29 @@ pub extern "C" fn kernel_main() -> ! {
         let kernel_virtual_address = KERNEL_ADDRESS.get_response().get().unwrap().virtual_base;
         debug!("kernel physical address: {:#x}", kernel_physical_address);
         debug!("kernel virtual address: {:#x}", kernel_virtual_address);
-        let offset = (kernel_virtual_address as i64) as usize;// - kernel_physical_address as i64) as usize;
-        MEM_MAPPER.lock().replace(unsafe { memory::init(VirtAddr::new(0)) });
+        let phys_mem_offset = *memory::PHYS_MEM_OFFSET;
+        debug!("direct map offset: {:#x}", phys_mem_offset.as_u64());
+        MEM_MAPPER.lock().replace(unsafe { memory::init(phys_mem_offset) });
         println!("[OK]");
         print!("initialising frame allocator...");
-        FRAME_ALLOC.lock().replace(unsafe { memory::BootInfoFrameAllocator::init() });
+        FRAME_ALLOC.lock().replace(unsafe { memory::BitmapFrameAllocator::init(phys_mem_offset) });
+        memory::slab::init(FRAME_ALLOC.lock().as_mut().unwrap());
         println!("[OK]");
+        {
+            let frame_alloc = FRAME_ALLOC.lock();
+            let frame_alloc = frame_alloc.as_ref().unwrap();
+            println!("{} KiB of {} KiB physical memory free", frame_alloc.free_frames() * 4, frame_alloc.total_frames() * 4);
+        }
         print!("initialising heap...");
         memory::allocator::init_heap(MEM_MAPPER.lock().as_mut().unwrap(), FRAME_ALLOC.lock().as_mut().unwrap()).expect("heap init failed");
         println!("[OK]");
+        print!("initialising mmio...");
+        memory::mmio::init();
+        println!("[OK]");
+        print!("hardening kernel mappings...");
+        let protections = security::hardening::harden_kernel();
+        println!("[OK]");
+        security::hardening::print_protections(&protections);
 
         print!("testing heap...");
         let reference_counted = Rc::new(vec![1, 2, 3]);
@@ -265,6 +183,18 @@ pub extern "C" fn kernel_main() -> ! {
             println!("[FAIL]");
         }
         drop(reference_counted);
+        #[cfg(feature = "f_debug_verbose")]
+        memory::allocator::print_heap_stats();
+    }
+
+    println!("debug: setup GDT");
+    {
+        internals::percpu::init_cpu();
+        internals::gdt::init_cpu();
+        println!("debug: GDT and TSS loaded");
+
+        // enable interrupts
+        x86_64::instructions::interrupts::enable();
     }
 
     // apic stuff
@@ -279,18 +209,60 @@ pub extern "C" fn kernel_main() -> ! {
         }
         print!("initialising apic...");
         //internals::cpu::tell_pic8259a_to_f_off();
-        let (addr, isos) = get_ioapic_info();
+        let (apic, processors) = get_apic_info();
+        internals::cpu::init_topology(&processors);
         unsafe { internals::cpu::enable_apic() };
+        internals::cpu::setup_lint_nmis(&apic.local_apic_nmi_lines);
         println!("[OK]");
+        match internals::cpu::apic_mode() {
+            internals::cpu::ApicMode::X2Apic => println!("local apic in x2apic mode"),
+            internals::cpu::ApicMode::XApic => println!("local apic in xapic mode (no x2apic support)"),
+        }
+        println!("{} cpu(s), {} ioapic(s)", internals::cpu::cpu_count(), apic.io_apics.len());
+        #[cfg(feature = "f_debug_verbose")]
+        internals::cpu::print_topology();
         print!("setting up apic interrupts...");
-        debug!("ioapicaddr: {:#x}", addr);
-        unsafe { internals::cpu::setup_ioapic(addr, isos) };
+        internals::interrupts::init_ioapics(&apic);
         println!("[OK]");
+        print!("requesting keyboard irq...");
+        match internals::interrupts::request_irq(internals::interrupts::IrqSource::Isa(1), internals::cpu::keyboard_irq) {
+            Ok(irq) => {
+                println!("[OK]");
+                debug!("keyboard on gsi {} vector {}", irq.gsi, irq.vector);
+            }
+            Err(e) => println!("[FAIL] {:?}", e),
+        }
         // enable interrupts
         //x86_64::instructions::interrupts::enable();
     }
 
-    loop {
-        x86_64::instructions::hlt();
+    print!("initialising hpet...");
+    match boot::get_hpet_info() {
+        Some(info) => {
+            time::hpet::init(&info);
+            println!("[OK]");
+        }
+        None => println!("[FAIL] (no hpet, falling back to the pit)"),
     }
+    print!("calibrating timers...");
+    time::init();
+    println!("[OK]");
+    println!("tsc {} MHz{}, lapic timer {} MHz", time::tsc_frequency() / 1_000_000,
+        if time::has_invariant_tsc() { " (invariant)" } else { "" }, time::lapic_timer_frequency() / 1_000_000);
+    print!("reading real-time clock...");
+    time::init_wall_clock();
+    println!("[OK]");
+    println!("it is {} utc", time::now_datetime());
+
+    print!("starting threads...");
+    task::init();
+    task::workqueue::init();
+    println!("[OK]");
+    print!("starting other cpus...");
+    let cpus_online = internals::smp::start_aps();
+    println!("[OK]");
+    println!("{} of {} cpu(s) online", cpus_online, internals::cpu::cpu_count());
+
+    // everything else happens on other threads from here on, the idle thread takes over once we're gone
+    task::exit();
 }
\ No newline at end of file
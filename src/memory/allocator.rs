@@ -1,35 +1,65 @@
 use core::alloc::GlobalAlloc;
-use core::mem;
 use core::ptr::NonNull;
-use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags};
 use x86_64::structures::paging::mapper::MapToError;
 use x86_64::VirtAddr;
-use crate::memory::PageSize;
+use crate::memory::{FRAME_ALLOC, MEM_MAPPER, PageSize, slab};
+use crate::memory::slab::{CacheStats, EmptySlabs, SlabCache};
+use crate::{debug, lock_class, println};
+use crate::sync::SpinLock;
 use super::Locked;
 
 pub const HEAP_START: u64 = 0x_4444_4444_0000;
-pub const HEAP_SIZE: u64 = 100 * 1024; // 100 KiB
+pub const HEAP_SIZE: u64 = 100 * 1024; // 100 KiB, initial size
+pub const HEAP_MAX_SIZE: u64 = 64 * 1024 * 1024; // 64 MiB, default ceiling
+pub const HEAP_GROW_STEP: u64 = 64 * 1024; // 64 KiB, minimum amount mapped when growing
+pub const HEAP_WINDOW_SIZE: u64 = 4 * 1024 * 1024 * 1024; // 4 GiB of address space, no limit goes past it
+
+// the heap never grows past HEAP_START + HEAP_LIMIT, can be changed at runtime with set_heap_limit
+static HEAP_LIMIT: AtomicU64 = AtomicU64::new(HEAP_MAX_SIZE);
+// bytes mapped from HEAP_START up, only changed by `grow`
+static HEAP_MAPPED: AtomicU64 = AtomicU64::new(0);
+// one grower at a time, the heap only ever grows at its top
+static HEAP_GROW: SpinLock<()> = SpinLock::named("HEAP_GROW", lock_class!(), ());
 
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+static ALLOCATOR: Locked<SlabAllocator> = Locked::named("ALLOCATOR", lock_class!(), SlabAllocator::new());
 
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+// anything bigger than the largest cache goes straight to the fallback heap
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024];
 
-pub struct FixedSizeBlockAllocator {
-    list_heads: [Option<&'static mut FixedSizeBlockNode>; BLOCK_SIZES.len()],
+/// general purpose kernel allocator: power of two slab caches for small objects,
+/// with a linked list heap for everything else
+pub struct SlabAllocator {
+    caches: [SlabCache; BLOCK_SIZES.len()],
     #[cfg(feature = "f_ll_alloc")]
     fallback_allocator: linked_list_allocator::Heap,
 }
 
-pub struct FixedSizeBlockNode {
-    next: Option<&'static mut FixedSizeBlockNode>,
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    pub caches: [CacheStats; BLOCK_SIZES.len()],
+    pub fallback_size: usize,
+    pub fallback_used: usize,
+    pub fallback_free: usize,
+    pub heap_limit: u64,
 }
 
-impl FixedSizeBlockAllocator {
+impl SlabAllocator {
     pub const fn new() -> Self {
-        const EMPTY: Option<&'static mut FixedSizeBlockNode> = None;
         Self {
-            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            caches: [
+                SlabCache::new("kmalloc-8", 8, 8),
+                SlabCache::new("kmalloc-16", 16, 16),
+                SlabCache::new("kmalloc-32", 32, 32),
+                SlabCache::new("kmalloc-64", 64, 64),
+                SlabCache::new("kmalloc-128", 128, 128),
+                SlabCache::new("kmalloc-256", 256, 256),
+                SlabCache::new("kmalloc-512", 512, 512),
+                SlabCache::new("kmalloc-1024", 1024, 1024),
+            ],
             #[cfg(feature = "f_ll_alloc")]
             fallback_allocator: linked_list_allocator::Heap::empty(),
         }
@@ -39,61 +69,238 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
-    fn fallback_alloc(&mut self, layout: core::alloc::Layout) -> *mut u8 {
+    // Err(size) means the heap has to grow by `size` bytes first
+    fn fallback_alloc(&mut self, layout: core::alloc::Layout) -> Result<*mut u8, u64> {
         match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => core::ptr::null_mut(),
+            Ok(ptr) => Ok(ptr.as_ptr()),
+            // worst case the allocation needs its whole size plus alignment padding past the top
+            Err(_) => Err(layout.size() as u64 + layout.align() as u64),
         }
     }
 }
 
-unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
+/// maps enough new pages at the top of the heap for `needed` more bytes, returns false if the
+/// ceiling would be exceeded or we ran out of physical memory. `seen` is the heap size the caller
+/// ran out at, if someone else grew it since then there's nothing to do but try again.
+/// the allocator lock mustn't be held, mapping takes MEM_MAPPER and FRAME_ALLOC, and code holding
+/// those may allocate
+fn grow(seen: u64, needed: u64) -> bool {
+    let _grow = HEAP_GROW.lock();
+    let heap_size = HEAP_MAPPED.load(Ordering::SeqCst);
+    if heap_size != seen {
+        return true;
+    }
+    let grow_by = ((needed.max(HEAP_GROW_STEP) + 4095) / 4096) * 4096;
+    let limit = HEAP_LIMIT.load(Ordering::SeqCst);
+    if heap_size + grow_by > limit {
+        debug!("heap: growing by {} bytes would exceed the {} byte ceiling", grow_by, limit);
+        return false;
+    }
+
+    let mapped = {
+        let mut mapper = MEM_MAPPER.lock();
+        let mut frame_alloc = FRAME_ALLOC.lock();
+        match (mapper.as_mut(), frame_alloc.as_mut()) {
+            (Some(mapper), Some(frame_alloc)) => map_heap_pages(HEAP_START + heap_size, grow_by, mapper, frame_alloc),
+            _ => 0,
+        }
+    };
+    if mapped > 0 {
+        debug!("heap: grew by {} bytes to {} bytes", mapped, heap_size + mapped);
+        unsafe { ALLOCATOR.lock().fallback_allocator.extend(mapped as usize) };
+        HEAP_MAPPED.store(heap_size + mapped, Ordering::SeqCst);
+    }
+    mapped == grow_by
+}
+
+/// maps up to `size` bytes of fresh frames starting at `start`, returning how many bytes were actually mapped
+fn map_heap_pages(
+    start: u64,
+    size: u64,
+    mapper: &mut impl Mapper<PageSize>,
+    frame_allocator: &mut (impl FrameAllocator<PageSize> + FrameDeallocator<PageSize>),
+) -> u64 {
+    let page_range = {
+        let start_page = Page::containing_address(VirtAddr::new(start));
+        let end_page = Page::containing_address(VirtAddr::new(start + size - 1));
+        Page::range_inclusive(start_page, end_page)
+    };
+
+    // never executable, but the nx bit is reserved until efer turns it on
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    let mut mapped = 0;
+    for page in page_range {
+        let frame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => break,
+        };
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(e) => {
+                debug!("heap: failed to map {:?}: {:?}", page, e);
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                break;
+            }
+        }
+        mapped += 4096;
+    }
+    mapped
+}
+
+/// changes the heap ceiling, it can't be lowered below what is already mapped
+pub fn set_heap_limit(limit: u64) {
+    // taken so the heap can't grow past the new limit while we're setting it
+    let _grow = HEAP_GROW.lock();
+    let limit = limit.max(HEAP_MAPPED.load(Ordering::SeqCst)).max(HEAP_SIZE).min(HEAP_WINDOW_SIZE);
+    HEAP_LIMIT.store(limit, Ordering::SeqCst);
+}
+
+pub fn heap_stats() -> HeapStats {
+    let allocator = ALLOCATOR.lock();
+    let mut caches = [CacheStats::default(); BLOCK_SIZES.len()];
+    for (stats, cache) in caches.iter_mut().zip(allocator.caches.iter()) {
+        *stats = cache.stats();
+    }
+    HeapStats {
+        caches,
+        fallback_size: allocator.fallback_allocator.size(),
+        fallback_used: allocator.fallback_allocator.used(),
+        fallback_free: allocator.fallback_allocator.free(),
+        heap_limit: HEAP_LIMIT.load(Ordering::Relaxed),
+    }
+}
+
+/// dumps every cache (general purpose and named) to the console, handy for spotting leaks
+pub fn print_heap_stats() {
+    let stats = heap_stats();
+    println!("---heap statistics---");
+    for cache in stats.caches.iter() {
+        cache.print();
+    }
+    slab::for_each_named_cache(|cache| cache.print());
+    println!("fallback heap: {} bytes mapped, {} used, {} free (ceiling {})",
+        stats.fallback_size, stats.fallback_used, stats.fallback_free, stats.heap_limit);
+}
+
+/// releases all empty slabs, both in the general purpose caches and in named caches
+pub fn reclaim_slabs() -> usize {
+    let mut empty = EmptySlabs::new();
+    for cache in ALLOCATOR.lock().caches.iter_mut() {
+        empty.append(cache.reclaim(0));
+    }
+    empty.release() + slab::reclaim_named_caches()
+}
+
+/// prints what we know about the heap when an allocation couldn't be satisfied
+pub fn oom_report(layout: core::alloc::Layout) {
+    println!("---KERNEL OUT OF MEMORY UWU---");
+    println!("failed allocation: {} bytes, align {}", layout.size(), layout.align());
+    // the allocator lock may be held by whoever ran out of memory, so don't wait on it
+    if let Some(allocator) = ALLOCATOR.inner.try_lock() {
+        let heap = &allocator.fallback_allocator;
+        println!("heap: {} bytes mapped, {} used, {} free", heap.size(), heap.used(), heap.free());
+    } else {
+        println!("heap: allocator locked, no statistics");
+    }
+    println!("heap ceiling: {} bytes", HEAP_LIMIT.load(Ordering::Relaxed));
+    if let Some(frame_alloc) = FRAME_ALLOC.try_lock() {
+        if let Some(frame_alloc) = frame_alloc.as_ref() {
+            println!("physical memory: {} of {} frames free", frame_alloc.free_frames(), frame_alloc.total_frames());
+        }
+    }
+}
+
+// what `alloc` has to do before trying again, done with the allocator unlocked since it means
+// taking FRAME_ALLOC (and MEM_MAPPER), and code holding those may allocate
+enum Refill {
+    /// the cache at this index needs a new slab
+    Slab(usize),
+    /// the heap has to grow by `needed` bytes, having run out at `seen`
+    Heap { seen: u64, needed: u64 },
+}
+
+impl SlabAllocator {
+    // `use_heap` sends small allocations to the heap instead of asking for a new slab
+    fn try_alloc(&mut self, layout: core::alloc::Layout, use_heap: bool) -> Result<*mut u8, Refill> {
+        let result = match list_index(&layout) {
             Some(index) => {
-                match allocator.list_heads[index].take() {
-                    Some(node) => {
-                        allocator.list_heads[index] = node.next.take();
-                        node as *mut FixedSizeBlockNode as *mut u8
-                    }
-                    None => {
-                        // no block exists in list => allocate new block
-                        let block_size = BLOCK_SIZES[index];
-                        // only works if all block sizes are a power of 2
-                        let block_align = block_size;
-                        let layout = core::alloc::Layout::from_size_align(block_size, block_align)
-                            .unwrap();
-                        allocator.fallback_alloc(layout)
+                if let Some(ptr) = self.caches[index].alloc() {
+                    return Ok(ptr.as_ptr());
+                }
+                if !use_heap && self.caches[index].can_grow() {
+                    return Err(Refill::Slab(index));
+                }
+                let block_size = BLOCK_SIZES[index];
+                // only works if all block sizes are a power of 2
+                let block_align = block_size;
+                let layout = core::alloc::Layout::from_size_align(block_size, block_align)
+                    .unwrap();
+                self.fallback_alloc(layout)
+            }
+            None => self.fallback_alloc(layout),
+        };
+        result.map_err(|needed| Refill::Heap { seen: self.fallback_allocator.size() as u64, needed })
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<SlabAllocator> {
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        let mut use_heap = false;
+        loop {
+            let refill = match self.lock().try_alloc(layout, use_heap) {
+                Ok(ptr) => return ptr,
+                Err(refill) => refill,
+            };
+            match refill {
+                Refill::Slab(index) => match slab::slab_frame() {
+                    Some(frame) => self.lock().caches[index].add_slab(frame),
+                    // no pages for a new slab (e.g. the frame allocator isn't up yet),
+                    // fall back to a block from the heap
+                    None => use_heap = true,
+                },
+                // out of space, map more memory and retry
+                Refill::Heap { seen, needed } => {
+                    if !grow(seen, needed) {
+                        return core::ptr::null_mut();
                     }
                 }
             }
-            None => allocator.fallback_alloc(layout),
         }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => {
-                let new_node = FixedSizeBlockNode {
-                    next: allocator.list_heads[index].take(),
-                };
-                // verify that block has size and alignment required for storing node
-                assert!(mem::size_of::<FixedSizeBlockNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<FixedSizeBlockNode>() <= BLOCK_SIZES[index]);
-                let new_node_ptr = ptr as *mut FixedSizeBlockNode;
-                new_node_ptr.write(new_node);
-                allocator.list_heads[index] = Some(&mut *new_node_ptr);
-            }
-            None => {
-                let ptr = NonNull::new(ptr).unwrap();
-                allocator.fallback_allocator.deallocate(ptr, layout);
+        let ptr = NonNull::new(ptr).unwrap();
+        let empty = {
+            let mut allocator = self.lock();
+            match list_index(&layout) {
+                Some(index) if !is_heap_address(ptr) => allocator.caches[index].free(ptr),
+                Some(index) => {
+                    let block_size = BLOCK_SIZES[index];
+                    let layout = core::alloc::Layout::from_size_align(block_size, block_size)
+                        .unwrap();
+                    allocator.fallback_allocator.deallocate(ptr, layout);
+                    EmptySlabs::new()
+                }
+                None => {
+                    allocator.fallback_allocator.deallocate(ptr, layout);
+                    EmptySlabs::new()
+                }
             }
-        }
+        };
+        empty.release();
     }
 }
 
+// slab objects live in the direct map, fallback blocks live in the heap window. the whole window,
+// not just up to the limit, which can move while blocks are out
+fn is_heap_address(ptr: NonNull<u8>) -> bool {
+    let addr = ptr.as_ptr() as u64;
+    addr >= HEAP_START && addr < HEAP_START + HEAP_WINDOW_SIZE
+}
+
 fn list_index(layout: &core::alloc::Layout) -> Option<usize> {
     let required_block_size = layout.size().max(layout.align());
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
@@ -101,29 +308,16 @@ fn list_index(layout: &core::alloc::Layout) -> Option<usize> {
 
 pub fn init_heap(
     mapper: &mut impl Mapper<PageSize>,
-    frame_allocator: &mut impl FrameAllocator<PageSize>,
+    frame_allocator: &mut (impl FrameAllocator<PageSize> + FrameDeallocator<PageSize>),
 ) -> Result<(), MapToError<PageSize>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush()
-        };
+    if map_heap_pages(HEAP_START, HEAP_SIZE, mapper, frame_allocator) != HEAP_SIZE {
+        return Err(MapToError::FrameAllocationFailed);
     }
 
     unsafe {
         ALLOCATOR.lock().init(HEAP_START as usize, HEAP_SIZE as usize);
     }
+    HEAP_MAPPED.store(HEAP_SIZE, Ordering::SeqCst);
 
     Ok(())
-}
\ No newline at end of file
+}
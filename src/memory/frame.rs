@@ -0,0 +1,182 @@
+use core::slice;
+use limine::LimineMemoryMapEntryType;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::boot::MEM_MAP;
+use crate::debug;
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = 64;
+
+/// physical frame allocator backed by a bitmap, one bit per 4 KiB frame (1 = used).
+/// the bitmap itself is carved out of the first usable region that is big enough to hold it
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    // one bit per frame the memory map calls usable, laid out like `bitmap` and right after it
+    usable: &'static mut [u64],
+    total_frames: usize,
+    free_frames: usize,
+    // every word below this index is known to be full
+    search_hint: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// builds the bitmap from the bootloader memory map.
+    /// `phys_mem_offset` is the virtual address that physical address 0 is mapped at
+    pub unsafe fn init(phys_mem_offset: VirtAddr) -> Self {
+        #[cfg(feature = "f_limine")] {
+            let mmap = MEM_MAP.get_response().get().expect("failed to get memory map")
+                .memmap();
+
+            // the bitmap has to cover every usable frame, so size it by the highest usable address
+            let highest_addr = mmap.iter()
+                .filter(|entry| entry.typ == LimineMemoryMapEntryType::Usable)
+                .map(|entry| entry.base + entry.len)
+                .max()
+                .expect("no usable memory");
+            let total_frames = (highest_addr / FRAME_SIZE) as usize;
+            let bitmap_words = (total_frames + BITS_PER_WORD - 1) / BITS_PER_WORD;
+            // room for the usable bitmap right after it as well
+            let bitmap_bytes = (bitmap_words * 8 * 2) as u64;
+
+            let bitmap_entry = mmap.iter()
+                .find(|entry| entry.typ == LimineMemoryMapEntryType::Usable && entry.len >= bitmap_bytes)
+                .expect("no usable region large enough for the frame bitmap");
+            let bitmap_phys = bitmap_entry.base;
+            debug!("frame bitmap: {} frames, {} bytes at phys {:#x}", total_frames, bitmap_bytes, bitmap_phys);
+
+            let bitmap_ptr = (phys_mem_offset + bitmap_phys).as_mut_ptr::<u64>();
+            let (bitmap, usable) = slice::from_raw_parts_mut(bitmap_ptr, bitmap_words * 2).split_at_mut(bitmap_words);
+            // start with everything marked as used and unusable, then free the usable regions
+            bitmap.fill(u64::MAX);
+            usable.fill(0);
+
+            let mut allocator = Self {
+                bitmap,
+                usable,
+                total_frames,
+                free_frames: 0,
+                search_hint: 0,
+            };
+
+            for entry in mmap.iter().filter(|entry| entry.typ == LimineMemoryMapEntryType::Usable) {
+                // usable entries are page aligned according to the limine spec, but don't trust that
+                let start = (entry.base + FRAME_SIZE - 1) / FRAME_SIZE;
+                let end = (entry.base + entry.len) / FRAME_SIZE;
+                for index in start..end {
+                    let index = index as usize;
+                    allocator.usable[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+                    allocator.clear(index);
+                }
+            }
+
+            // don't hand out the frames the bitmap lives in
+            let bitmap_start = bitmap_phys / FRAME_SIZE;
+            let bitmap_end = (bitmap_phys + bitmap_bytes + FRAME_SIZE - 1) / FRAME_SIZE;
+            for index in bitmap_start..bitmap_end {
+                allocator.set(index as usize);
+            }
+
+            debug!("frame allocator: {} of {} frames free", allocator.free_frames, allocator.total_frames);
+            allocator
+        }
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// takes `count` physically contiguous frames and returns the first. a linear scan of the
+    /// whole bitmap, meant for setting things up at boot
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        if count == 0 || count > self.free_frames {
+            return None;
+        }
+        let mut run = 0;
+        for index in 0..self.total_frames {
+            if self.is_used(index) {
+                run = 0;
+                continue;
+            }
+            run += 1;
+            if run == count {
+                let start = index + 1 - count;
+                for frame in start..=index {
+                    self.set(frame);
+                }
+                return Some(PhysFrame::containing_address(PhysAddr::new(start as u64 * FRAME_SIZE)));
+            }
+        }
+        None
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set(&mut self, index: usize) {
+        if !self.is_used(index) {
+            self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+            self.free_frames -= 1;
+        }
+    }
+
+    fn clear(&mut self, index: usize) {
+        if self.is_used(index) {
+            self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+            self.free_frames += 1;
+        }
+    }
+
+    /// checks that a frame lies inside a usable region of the memory map, so that
+    /// reserved/acpi/mmio frames that happened to be mapped can never end up in the free pool
+    fn is_usable(&self, frame: PhysFrame) -> bool {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        index < self.total_frames && self.usable[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.free_frames == 0 {
+            return None;
+        }
+        for word_index in self.search_hint..self.bitmap.len() {
+            let word = self.bitmap[word_index];
+            if word == u64::MAX {
+                continue;
+            }
+            self.search_hint = word_index;
+            let index = word_index * BITS_PER_WORD + word.trailing_ones() as usize;
+            if index >= self.total_frames {
+                break;
+            }
+            self.set(index);
+            return Some(PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE)));
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        if !self.is_usable(frame) {
+            debug!("deallocate_frame: refusing to free non-usable frame {:?}", frame);
+            return;
+        }
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        if !self.is_used(index) {
+            debug!("deallocate_frame: double free of frame {:?}", frame);
+            return;
+        }
+        self.clear(index);
+        let word_index = index / BITS_PER_WORD;
+        if word_index < self.search_hint {
+            self.search_hint = word_index;
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::mem;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::debug;
+use crate::memory::{FRAME_ALLOC, MEM_MAPPER, PageSize};
+use crate::memory::tlb::TlbBatch;
+use crate::memory::vmalloc::{self, VmaKind};
+
+const PAGE_SIZE: u64 = 4096;
+const IA32_PAT: u32 = 0x277;
+
+// PA0 = WB, PA1 = WC, PA2 = UC-, PA3 = UC, upper half mirrors the lower half.
+// this is the same layout linux uses, it lets us select write-combining with PWT alone
+// so we never have to set the PAT bit (which shares bit 7 with HUGE_PAGE on 4 KiB entries)
+const PAT_LAYOUT: u64 = 0x00_07_01_06_00_07_01_06;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheType {
+    /// strongly uncached, for registers
+    Uncached,
+    /// write-combining, for framebuffers and similar
+    WriteCombining,
+}
+
+impl CacheType {
+    fn flags(&self) -> PageTableFlags {
+        match self {
+            CacheType::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            CacheType::WriteCombining => PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// a mapping of device memory, unmapped again when dropped
+pub struct MmioRegion {
+    phys: PhysAddr,
+    virt: VirtAddr,
+    // offset of `phys` into the first mapped page
+    page_offset: u64,
+    size: usize,
+}
+
+pub fn check_pat_compat() -> bool {
+    let cpuid = unsafe { __cpuid(1) };
+    cpuid.edx & (1 << 16) != 0
+}
+
+/// programs the PAT so that `CacheType::WriteCombining` actually means write-combining.
+/// has to run on every cpu before it touches a write-combining mapping
+pub fn init() {
+    if !check_pat_compat() {
+        debug!("mmio: no PAT support, write-combining mappings will be write-through");
+        return;
+    }
+    unsafe {
+        let mut pat = Msr::new(IA32_PAT);
+        debug!("mmio: old PAT {:#x}", pat.read());
+        // caches must be flushed when changing memory types
+        asm!("wbinvd");
+        pat.write(PAT_LAYOUT);
+        x86_64::instructions::tlb::flush_all();
+    }
+}
+
+/// maps `size` bytes of device memory starting at `phys` as uncached
+pub fn ioremap(phys: PhysAddr, size: usize) -> Result<MmioRegion, MapToError<PageSize>> {
+    map_mmio(phys, size, CacheType::Uncached)
+}
+
+/// maps `size` bytes of device memory starting at `phys` as write-combining
+pub fn ioremap_wc(phys: PhysAddr, size: usize) -> Result<MmioRegion, MapToError<PageSize>> {
+    map_mmio(phys, size, CacheType::WriteCombining)
+}
+
+pub fn map_mmio(phys: PhysAddr, size: usize, cache: CacheType) -> Result<MmioRegion, MapToError<PageSize>> {
+    let page_offset = phys.as_u64() % PAGE_SIZE;
+    let phys_start = phys.align_down(PAGE_SIZE);
+    let num_pages = (page_offset + size as u64 + PAGE_SIZE - 1) / PAGE_SIZE;
+
+    let vma = match vmalloc::reserve(num_pages * PAGE_SIZE, VmaKind::Mmio) {
+        Some(vma) => vma,
+        None => {
+            debug!("mmio: no virtual space left while mapping {:#x} ({} bytes)", phys.as_u64(), size);
+            return Err(MapToError::FrameAllocationFailed);
+        }
+    };
+    let virt_start = vma.start.as_u64();
+    debug!("mmio: mapping phys {:#x} ({} pages) at {:#x} as {:?}", phys_start.as_u64(), num_pages, virt_start, cache);
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | cache.flags();
+    let mapped = {
+        let mut mapper = MEM_MAPPER.lock();
+        let mapper = mapper.as_mut().unwrap();
+        let mut frame_alloc = FRAME_ALLOC.lock();
+        let frame_alloc = frame_alloc.as_mut().unwrap();
+        let mut mapped = Ok(());
+        for i in 0..num_pages {
+            let page: Page<PageSize> = Page::containing_address(VirtAddr::new(virt_start + i * PAGE_SIZE));
+            let frame = PhysFrame::containing_address(phys_start + i * PAGE_SIZE);
+            let res = unsafe { mapper.map_to(page, frame, flags, frame_alloc) };
+            match res {
+                Ok(flush) => flush.flush(),
+                Err(e) => {
+                    // undo what we mapped so far. nobody else has seen these addresses yet, so
+                    // flushing them here is enough
+                    for j in 0..i {
+                        let page: Page<PageSize> = Page::containing_address(VirtAddr::new(virt_start + j * PAGE_SIZE));
+                        if let Ok((_, flush)) = mapper.unmap(page) {
+                            flush.flush();
+                        }
+                    }
+                    mapped = Err(e);
+                    break;
+                }
+            }
+        }
+        mapped
+    };
+    if let Err(e) = mapped {
+        // only once the mapper is unlocked, giving the range back may need the heap
+        vmalloc::release(vma.start);
+        return Err(e);
+    }
+
+    Ok(MmioRegion {
+        phys,
+        virt: VirtAddr::new(virt_start + page_offset),
+        page_offset,
+        size,
+    })
+}
+
+impl MmioRegion {
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.virt.as_mut_ptr()
+    }
+
+    /// keeps the mapping around forever, for devices we never let go of (lapic, ioapic)
+    pub fn leak(self) -> VirtAddr {
+        let virt = self.virt;
+        mem::forget(self);
+        virt
+    }
+
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + mem::size_of::<T>() <= self.size, "mmio read out of bounds");
+        unsafe { (self.virt + offset).as_ptr::<T>().read_volatile() }
+    }
+
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        assert!(offset + mem::size_of::<T>() <= self.size, "mmio write out of bounds");
+        unsafe { (self.virt + offset).as_mut_ptr::<T>().write_volatile(value) }
+    }
+
+    pub fn read32(&self, offset: usize) -> u32 {
+        self.read(offset)
+    }
+
+    pub fn write32(&self, offset: usize, value: u32) {
+        self.write(offset, value)
+    }
+
+    pub fn read64(&self, offset: usize) -> u64 {
+        self.read(offset)
+    }
+
+    pub fn write64(&self, offset: usize, value: u64) {
+        self.write(offset, value)
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        let virt_start = self.virt.as_u64() - self.page_offset;
+        let num_pages = (self.page_offset + self.size as u64 + PAGE_SIZE - 1) / PAGE_SIZE;
+        let mut batch = TlbBatch::new();
+        {
+            let mut mapper = MEM_MAPPER.lock();
+            let mapper = mapper.as_mut().unwrap();
+            for i in 0..num_pages {
+                let page: Page<PageSize> = Page::containing_address(VirtAddr::new(virt_start + i * PAGE_SIZE));
+                // the frames belong to the device, so they are not handed back to the frame allocator
+                match mapper.unmap(page) {
+                    Ok((_, flush)) => batch.add(page, flush),
+                    Err(e) => debug!("mmio: failed to unmap {:?}: {:?}", page, e),
+                }
+            }
+        }
+        batch.flush();
+        vmalloc::release(VirtAddr::new(virt_start));
+    }
+}
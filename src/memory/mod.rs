@@ -1,33 +1,40 @@
 pub mod allocator;
+pub mod frame;
+pub mod mmio;
+pub mod slab;
+pub mod stack;
+pub mod tlb;
+pub mod vmalloc;
 
-use alloc::boxed::Box;
-use alloc::sync::Arc;
 use lazy_static::lazy_static;
-use limine::LimineMemoryMapEntryType;
-use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate};
+use x86_64::structures::paging::{OffsetPageTable, PageTable, Size4KiB, Translate};
 use x86_64::{PhysAddr, VirtAddr};
 
 lazy_static!{
-    pub static ref MEM_MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
-    pub static ref FRAME_ALLOC: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+    pub static ref MEM_MAPPER: SpinLock<Option<OffsetPageTable<'static>>> = SpinLock::named("MEM_MAPPER", lock_class!(), None);
+    pub static ref FRAME_ALLOC: SpinLock<Option<BitmapFrameAllocator>> = SpinLock::named("FRAME_ALLOC", lock_class!(), None);
+    /// virtual address that physical address 0 is mapped at by the bootloader's direct map
+    pub static ref PHYS_MEM_OFFSET: VirtAddr = get_hhdm_offset();
 }
 
 pub const VIRT_MEM_OFFSET: u64 = 0xffffffff80000000;
 
 pub type PageSize = Size4KiB;
 
+// interrupt safe, so interrupt handlers and timer callbacks can allocate
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: SpinLock<A>,
 }
 
 impl<A> Locked<A> {
-    pub const fn new(inner: A) -> Self {
+    pub const fn named(name: &'static str, class: &'static LockClassKey, inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: SpinLock::named(name, class, inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<A> {
         self.inner.lock()
     }
 }
@@ -49,100 +56,17 @@ unsafe fn active_level_4_table(phys_mem_offset: VirtAddr) -> &'static mut PageTa
     unsafe { &mut  *page_table_ptr } // unsafe
 }
 
-use spin::Mutex;
-use crate::{debug, print, println};
-use crate::boot::{KERNEL_ADDRESS, MEM_MAP};
+use crate::lock_class;
+use crate::sync::{LockClassKey, SpinLock, SpinLockGuard};
+use crate::boot::get_hhdm_offset;
+pub use frame::BitmapFrameAllocator;
 
-pub struct BootInfoFrameAllocator {
-    next: usize,
+/// translates a physical address into its alias in the higher half direct map
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    *PHYS_MEM_OFFSET + addr.as_u64()
 }
 
-impl BootInfoFrameAllocator {
-    pub unsafe fn init() -> Self {
-        Self {
-            next: 0,
-        }
-    }
-}
-
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        #[cfg(feature = "f_limine")] {
-            let mmap = MEM_MAP.get_response().get().expect("failed to get memory map")
-                .memmap();
-            let mut usable_frames = mmap.iter()
-                .filter(|entry| entry.typ == LimineMemoryMapEntryType::Usable)
-                    .map(|area| {
-                        let frame_addr = area.base;
-                        let frame_end = area.base + area.len;
-                        let frame_size = frame_end - frame_addr;
-                        let num_frames = frame_size / 4096;
-                        let start_frame = PhysFrame::containing_address(PhysAddr::new(frame_addr));
-                        (0..num_frames).map(move |i| start_frame + i)
-                    })
-                    .flatten();
-            let frame = usable_frames.nth(self.next).clone();
-            self.next += 1;
-
-            frame
-        }
-    }
-}
-
-pub fn read_phys_memory32(addr: u32) -> u32 {
-    let initaladdr = VirtAddr::new(addr as u64);
-    let addr = unsafe { MEM_MAPPER.lock().as_mut().unwrap().translate_addr(initaladdr) };
-    if let Some(addr) = addr {
-        let addr = addr.as_u64() as *const u32;
-        unsafe { *addr }
-    } else {
-        debug!("read_phys_memory32: addr {:x} not mapped", initaladdr.as_u64());
-        // map the page
-        let frame = FRAME_ALLOC.lock().as_mut().unwrap().allocate_frame().unwrap();
-        debug!("allocated frame: {:?}", frame);
-        let flags = x86_64::structures::paging::PageTableFlags::PRESENT | x86_64::structures::paging::PageTableFlags::WRITABLE;
-        let page = x86_64::structures::paging::Page::containing_address(initaladdr);
-        debug!("mapped page: {:?}", page);
-        let map_to_result = unsafe { MEM_MAPPER.lock().as_mut().unwrap().map_to(page, frame, flags, FRAME_ALLOC.lock().as_mut().unwrap()) };
-        debug!("map_to_result: {:?}", map_to_result);
-        if map_to_result.is_err() {
-            panic!("Failed to map page");
-        }
-        let addr = unsafe { MEM_MAPPER.lock().as_mut().unwrap().translate_addr(initaladdr) };
-        if let Some(addr) = addr {
-            let addr = addr.as_u64() as *const u32;
-            unsafe { *addr }
-        } else {
-            panic!("Failed to map page");
-        }
-    }
+/// translates a virtual address back into a physical one by walking the active page tables
+pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
+    MEM_MAPPER.lock().as_ref().and_then(|mapper| mapper.translate_addr(addr))
 }
-
-pub fn write_phys_memory32(addr: u32, value: u32) {
-    let initaladdr = VirtAddr::new(addr as u64);
-    let addr = unsafe { MEM_MAPPER.lock().as_mut().unwrap().translate_addr(initaladdr) };
-    if let Some(addr) = addr {
-        let addr = addr.as_u64() as *mut u32;
-        unsafe { *addr = value };
-    } else {
-        debug!("write_phys_memory32: addr {:x} not mapped", initaladdr.as_u64());
-        // map the page
-        let frame = FRAME_ALLOC.lock().as_mut().unwrap().allocate_frame().unwrap();
-        debug!("allocated frame: {:?}", frame);
-        let flags = x86_64::structures::paging::PageTableFlags::PRESENT | x86_64::structures::paging::PageTableFlags::WRITABLE;
-        let page = x86_64::structures::paging::Page::containing_address(initaladdr);
-        debug!("mapped page: {:?}", page);
-        let map_to_result = unsafe { MEM_MAPPER.lock().as_mut().unwrap().map_to(page, frame, flags, FRAME_ALLOC.lock().as_mut().unwrap()) };
-        debug!("map_to_result: {:?}", map_to_result);
-        if map_to_result.is_err() {
-            panic!("Failed to map page");
-        }
-        let addr = unsafe { MEM_MAPPER.lock().as_mut().unwrap().translate_addr(initaladdr) };
-        if let Some(addr) = addr {
-            let addr = addr.as_u64() as *mut u32;
-            unsafe { *addr = value };
-        } else {
-            panic!("Failed to map page");
-        }
-    }
-}
\ No newline at end of file
@@ -0,0 +1,476 @@
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+use x86_64::PhysAddr;
+use crate::memory::{BitmapFrameAllocator, FRAME_ALLOC, Locked, PHYS_MEM_OFFSET, phys_to_virt};
+use crate::{debug, lock_class, println};
+use crate::sync::{LockClassKey, SpinLock};
+
+pub const SLAB_SIZE: usize = 4096;
+/// how many completely empty slabs a cache keeps around before handing pages back
+pub const MAX_EMPTY_SLABS: usize = 1;
+pub const MAX_NAMED_CACHES: usize = 16;
+
+static CACHE_REGISTRY: SpinLock<[Option<&'static Locked<SlabCache>>; MAX_NAMED_CACHES]> = SpinLock::named("CACHE_REGISTRY", lock_class!(), [None; MAX_NAMED_CACHES]);
+
+// headers of off-slab slabs, one slot per physical frame like linux's struct page, set up by `init`
+static OFF_SLAB_HEADERS: AtomicPtr<SlabHeader> = AtomicPtr::new(ptr::null_mut());
+static OFF_SLAB_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+// every slab is one page. small objects share it with this header at the very start, so the slab an
+// object belongs to is just the object's address rounded down to the page. big ones would lose a
+// whole object to it, so their header lives in the frame's slot in OFF_SLAB_HEADERS instead
+#[repr(C)]
+struct SlabHeader {
+    next: *mut SlabHeader,
+    prev: *mut SlabHeader,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+struct SlabList {
+    head: *mut SlabHeader,
+    len: usize,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        Self {
+            head: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, slab: *mut SlabHeader) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = slab;
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut SlabHeader) {
+        if (*slab).prev.is_null() {
+            self.head = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        (*slab).next = ptr::null_mut();
+        (*slab).prev = ptr::null_mut();
+        self.len -= 1;
+    }
+
+    unsafe fn pop(&mut self) -> Option<*mut SlabHeader> {
+        let slab = self.head;
+        if slab.is_null() {
+            None
+        } else {
+            self.remove(slab);
+            Some(slab)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub objects_in_use: usize,
+    pub slabs_partial: usize,
+    pub slabs_full: usize,
+    pub slabs_empty: usize,
+    pub allocations: u64,
+    pub frees: u64,
+    pub slabs_reclaimed: u64,
+}
+
+impl CacheStats {
+    pub fn total_slabs(&self) -> usize {
+        self.slabs_partial + self.slabs_full + self.slabs_empty
+    }
+
+    pub fn print(&self) {
+        println!("{:<16} size {:>5} | {:>6} objs in use, {:>4} per slab | slabs: {} partial, {} full, {} empty | {} allocs, {} frees, {} reclaimed",
+            self.name, self.object_size, self.objects_in_use, self.objects_per_slab,
+            self.slabs_partial, self.slabs_full, self.slabs_empty,
+            self.allocations, self.frees, self.slabs_reclaimed);
+    }
+}
+
+/// a kmem_cache style object cache. objects are carved out of single page slabs taken
+/// straight from the frame allocator and reached through the direct map
+pub struct SlabCache {
+    name: &'static str,
+    object_size: usize,
+    first_object: usize,
+    objects_per_slab: usize,
+    // header in OFF_SLAB_HEADERS rather than at the start of the slab
+    off_slab: bool,
+    partial: SlabList,
+    full: SlabList,
+    empty: SlabList,
+    allocations: u64,
+    frees: u64,
+    slabs_reclaimed: u64,
+}
+
+// the raw pointers only ever point into slab pages owned by this cache
+unsafe impl Send for SlabCache {}
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+impl SlabCache {
+    /// `align` must be a power of two, objects have to fit in a page alongside the slab header
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        let align = if align < mem::align_of::<FreeObject>() { mem::align_of::<FreeObject>() } else { align };
+        let size = if size < mem::size_of::<FreeObject>() { mem::size_of::<FreeObject>() } else { size };
+        let object_size = align_up(size, align);
+        // past an eighth of the slab the header costs at least an eighth of it
+        let off_slab = object_size >= SLAB_SIZE / 8;
+        let first_object = if off_slab { 0 } else { align_up(mem::size_of::<SlabHeader>(), align) };
+        assert!(first_object + object_size <= SLAB_SIZE, "slab object too large");
+        Self {
+            name,
+            object_size,
+            first_object,
+            objects_per_slab: (SLAB_SIZE - first_object) / object_size,
+            off_slab,
+            partial: SlabList::new(),
+            full: SlabList::new(),
+            empty: SlabList::new(),
+            allocations: 0,
+            frees: 0,
+            slabs_reclaimed: 0,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    /// takes an object from the slabs the cache already has. None means it needs a new slab:
+    /// get a frame with `slab_frame` once the cache is unlocked, and hand it over with `add_slab`
+    pub fn alloc(&mut self) -> Option<NonNull<u8>> {
+        unsafe {
+            let slab = match self.partial.head {
+                slab if !slab.is_null() => slab,
+                _ => {
+                    let slab = self.empty.pop()?;
+                    self.partial.push(slab);
+                    slab
+                }
+            };
+
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).in_use += 1;
+            if (*slab).in_use == self.objects_per_slab {
+                self.partial.remove(slab);
+                self.full.push(slab);
+            }
+            self.allocations += 1;
+            NonNull::new(object as *mut u8)
+        }
+    }
+
+    /// whether `add_slab` can take a frame. off-slab caches can't before `init`
+    pub fn can_grow(&self) -> bool {
+        !self.off_slab || !OFF_SLAB_HEADERS.load(Ordering::SeqCst).is_null()
+    }
+
+    /// `ptr` must have come from `alloc` on this same cache. returns the slabs the cache no longer
+    /// wants, to be released once it's unlocked
+    pub unsafe fn free(&mut self, ptr: NonNull<u8>) -> EmptySlabs {
+        let page = ptr.as_ptr() as usize & !(SLAB_SIZE - 1);
+        let slab = if self.off_slab {
+            // slabs are always reached through the direct map
+            let frame = PhysFrame::containing_address(PhysAddr::new(page as u64 - PHYS_MEM_OFFSET.as_u64()));
+            off_slab_header(frame)
+        } else {
+            page as *mut SlabHeader
+        };
+        let was_full = (*slab).in_use == self.objects_per_slab;
+
+        let object = ptr.as_ptr() as *mut FreeObject;
+        (*object).next = (*slab).free;
+        (*slab).free = object;
+        (*slab).in_use -= 1;
+        self.frees += 1;
+
+        if was_full {
+            self.full.remove(slab);
+        } else {
+            self.partial.remove(slab);
+        }
+        if (*slab).in_use == 0 {
+            self.empty.push(slab);
+            if self.empty.len > MAX_EMPTY_SLABS {
+                return self.reclaim(MAX_EMPTY_SLABS);
+            }
+        } else {
+            self.partial.push(slab);
+        }
+        EmptySlabs::new()
+    }
+
+    /// takes every empty slab beyond `keep` out of the cache, release them once it's unlocked
+    pub fn reclaim(&mut self, keep: usize) -> EmptySlabs {
+        let mut slabs = EmptySlabs::new();
+        while self.empty.len > keep {
+            unsafe { slabs.list.push(self.empty.pop().unwrap()) };
+        }
+        self.slabs_reclaimed += slabs.list.len as u64;
+        slabs
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let slabs_partial = self.partial.len;
+        let slabs_full = self.full.len;
+        let mut objects_in_use = slabs_full * self.objects_per_slab;
+        let mut slab = self.partial.head;
+        while !slab.is_null() {
+            unsafe {
+                objects_in_use += (*slab).in_use;
+                slab = (*slab).next;
+            }
+        }
+        CacheStats {
+            name: self.name,
+            object_size: self.object_size,
+            objects_per_slab: self.objects_per_slab,
+            objects_in_use,
+            slabs_partial,
+            slabs_full,
+            slabs_empty: self.empty.len,
+            allocations: self.allocations,
+            frees: self.frees,
+            slabs_reclaimed: self.slabs_reclaimed,
+        }
+    }
+
+    /// turns `frame` (from `slab_frame`) into an empty slab of this cache, check `can_grow` first
+    pub unsafe fn add_slab(&mut self, frame: PhysFrame) {
+        let base = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+
+        // thread every object onto the free list, lowest address first
+        let mut free: *mut FreeObject = ptr::null_mut();
+        for i in (0..self.objects_per_slab).rev() {
+            let object = base.add(self.first_object + i * self.object_size) as *mut FreeObject;
+            (*object).next = free;
+            free = object;
+        }
+
+        let slab = if self.off_slab { off_slab_header(frame) } else { base as *mut SlabHeader };
+        slab.write(SlabHeader {
+            next: ptr::null_mut(),
+            prev: ptr::null_mut(),
+            free,
+            in_use: 0,
+        });
+        self.empty.push(slab);
+    }
+}
+
+/// sets aside the off-slab headers, taking them from `frame_alloc` in one physically contiguous run.
+/// until this has run the off-slab caches can't grow, and their allocations go to the heap
+pub fn init(frame_alloc: &mut BitmapFrameAllocator) {
+    let frames = frame_alloc.total_frames();
+    let table_frames = (frames * mem::size_of::<SlabHeader>() + SLAB_SIZE - 1) / SLAB_SIZE;
+    match frame_alloc.allocate_contiguous(table_frames) {
+        Some(table) => {
+            debug!("slab: {} KiB of off-slab headers at phys {:#x}", table_frames * 4, table.start_address().as_u64());
+            OFF_SLAB_FRAMES.store(frames, Ordering::SeqCst);
+            OFF_SLAB_HEADERS.store(phys_to_virt(table.start_address()).as_mut_ptr(), Ordering::SeqCst);
+        }
+        None => debug!("slab: no room for off-slab headers, big objects will come from the heap"),
+    }
+}
+
+fn off_slab_header(frame: PhysFrame) -> *mut SlabHeader {
+    let index = (frame.start_address().as_u64() / SLAB_SIZE as u64) as usize;
+    assert!(index < OFF_SLAB_FRAMES.load(Ordering::SeqCst), "slab frame {:?} outside the header table", frame);
+    unsafe { OFF_SLAB_HEADERS.load(Ordering::SeqCst).add(index) }
+}
+
+// the page a slab header describes, wherever the header is
+fn slab_frame_of(slab: *mut SlabHeader) -> PhysFrame {
+    let table = OFF_SLAB_HEADERS.load(Ordering::SeqCst);
+    let table_end = table.wrapping_add(OFF_SLAB_FRAMES.load(Ordering::SeqCst));
+    if slab >= table && slab < table_end {
+        let index = (slab as usize - table as usize) / mem::size_of::<SlabHeader>();
+        PhysFrame::containing_address(PhysAddr::new((index * SLAB_SIZE) as u64))
+    } else {
+        // on-slab headers are always reached through the direct map
+        PhysFrame::containing_address(PhysAddr::new(slab as u64 - PHYS_MEM_OFFSET.as_u64()))
+    }
+}
+
+/// a page for a new slab. the frame allocator is taken after (never while) a cache is locked
+pub fn slab_frame() -> Option<PhysFrame> {
+    FRAME_ALLOC.lock().as_mut()?.allocate_frame()
+}
+
+/// slabs taken out of a cache, which go back to the frame allocator with `release`
+#[must_use = "the slabs' frames are lost unless released"]
+pub struct EmptySlabs {
+    list: SlabList,
+}
+
+impl EmptySlabs {
+    pub const fn new() -> EmptySlabs {
+        EmptySlabs { list: SlabList::new() }
+    }
+
+    pub fn append(&mut self, mut other: EmptySlabs) {
+        while let Some(slab) = unsafe { other.list.pop() } {
+            unsafe { self.list.push(slab) };
+        }
+    }
+
+    /// gives the frames back, returns how many there were. don't hold a cache lock
+    pub fn release(mut self) -> usize {
+        let released = self.list.len;
+        if released == 0 {
+            return 0;
+        }
+        let mut frame_alloc = FRAME_ALLOC.lock();
+        while let Some(slab) = unsafe { self.list.pop() } {
+            if let Some(frame_alloc) = frame_alloc.as_mut() {
+                unsafe { frame_alloc.deallocate_frame(slab_frame_of(slab)) };
+            }
+        }
+        released
+    }
+}
+
+impl Locked<SlabCache> {
+    /// an object from a named cache, None if there's no memory for another slab
+    pub fn alloc(&self) -> Option<NonNull<u8>> {
+        loop {
+            {
+                let mut cache = self.lock();
+                if let Some(ptr) = cache.alloc() {
+                    return Some(ptr);
+                }
+                if !cache.can_grow() {
+                    return None;
+                }
+            }
+            let frame = slab_frame()?;
+            unsafe { self.lock().add_slab(frame) };
+        }
+    }
+
+    /// `ptr` must have come from `alloc` on this same cache
+    pub unsafe fn free(&self, ptr: NonNull<u8>) {
+        let empty = self.lock().free(ptr);
+        empty.release();
+    }
+}
+
+/// a named cache of `T`s, for kernel objects that come and go often. it registers itself the first
+/// time it's used, so it shows up in the heap statistics
+pub struct KmemCache<T> {
+    cache: Locked<SlabCache>,
+    registered: AtomicBool,
+    _marker: PhantomData<T>,
+}
+
+// the cache only hands out memory, the `T`s in it belong to their `CacheBox`es
+unsafe impl<T> Sync for KmemCache<T> {}
+
+impl<T> KmemCache<T> {
+    /// `class` should come from `lock_class!()` at the definition, so each cache is its own lockdep class
+    pub const fn new(name: &'static str, class: &'static LockClassKey) -> KmemCache<T> {
+        KmemCache {
+            cache: Locked::named(name, class, SlabCache::new(name, mem::size_of::<T>(), mem::align_of::<T>())),
+            registered: AtomicBool::new(false),
+            _marker: PhantomData,
+        }
+    }
+
+    /// moves `value` into the cache, None if there's no memory for another slab
+    pub fn alloc(&'static self, value: T) -> Option<CacheBox<T>> {
+        if !self.registered.swap(true, Ordering::SeqCst) {
+            register_cache(&self.cache);
+        }
+        let ptr = self.cache.alloc()?.cast::<T>();
+        unsafe { ptr.as_ptr().write(value) };
+        Some(CacheBox { ptr, cache: self })
+    }
+}
+
+/// a `T` living in a `KmemCache`, like a `Box` but given back to the cache when dropped
+pub struct CacheBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static KmemCache<T>,
+}
+
+unsafe impl<T: Send> Send for CacheBox<T> {}
+unsafe impl<T: Sync> Sync for CacheBox<T> {}
+
+impl<T> Deref for CacheBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for CacheBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for CacheBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.cache.free(self.ptr.cast());
+        }
+    }
+}
+
+/// makes a named cache show up in `heap_stats`
+pub fn register_cache(cache: &'static Locked<SlabCache>) {
+    let mut registry = CACHE_REGISTRY.lock();
+    match registry.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(cache),
+        None => panic!("too many slab caches registered (max {})", MAX_NAMED_CACHES),
+    }
+}
+
+/// calls `f` with the statistics of every registered named cache
+pub fn for_each_named_cache(mut f: impl FnMut(CacheStats)) {
+    for cache in CACHE_REGISTRY.lock().iter().flatten() {
+        f(cache.lock().stats());
+    }
+}
+
+/// releases every empty slab of every registered named cache
+pub fn reclaim_named_caches() -> usize {
+    CACHE_REGISTRY.lock().iter().flatten().map(|cache| {
+        let empty = cache.lock().reclaim(0);
+        empty.release()
+    }).sum()
+}
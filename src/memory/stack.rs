@@ -0,0 +1,69 @@
+use core::mem;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::VirtAddr;
+use crate::memory::PageSize;
+use crate::memory::vmalloc::{self, VmArea, VmaKind};
+
+pub const KERNEL_STACK_SIZE: u64 = 4096 * 5;
+pub const GUARD_PAGE_SIZE: u64 = 4096;
+
+/// a kernel stack mapped from the vmalloc window, with an unmapped guard page right below it
+/// so running off the end faults instead of scribbling over whatever comes next
+pub struct KernelStack {
+    vma: VmArea,
+}
+
+/// allocates a stack of at least `size` bytes (rounded up to whole pages)
+pub fn alloc_stack(size: u64) -> Result<KernelStack, MapToError<PageSize>> {
+    let vma = vmalloc::reserve(GUARD_PAGE_SIZE + size, VmaKind::Stack).ok_or(MapToError::FrameAllocationFailed)?;
+    // everything but the lowest page gets backed, that one stays as the guard
+    let backed = VmArea {
+        start: vma.start + GUARD_PAGE_SIZE,
+        size: vma.size - GUARD_PAGE_SIZE,
+        kind: vma.kind,
+    };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    if let Err(e) = vmalloc::map_fresh_pages(&backed, flags) {
+        vmalloc::vfree(vma.start);
+        return Err(e);
+    }
+    Ok(KernelStack { vma })
+}
+
+impl KernelStack {
+    /// initial stack pointer, stacks grow down from here
+    pub fn top(&self) -> VirtAddr {
+        self.vma.end()
+    }
+
+    /// lowest usable address, just above the guard page
+    pub fn bottom(&self) -> VirtAddr {
+        self.vma.start + GUARD_PAGE_SIZE
+    }
+
+    pub fn size(&self) -> u64 {
+        self.vma.size - GUARD_PAGE_SIZE
+    }
+
+    /// keeps the stack around forever, for the tss stacks that live as long as the cpu does
+    pub fn leak(self) -> VirtAddr {
+        let top = self.top();
+        mem::forget(self);
+        top
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        vmalloc::vfree(self.vma.start);
+    }
+}
+
+/// if `addr` lies in the guard page of a kernel stack, returns that stack's area.
+/// safe to call from fault handlers, it gives up instead of waiting on the vma lock
+pub fn guard_page_hit(addr: VirtAddr) -> Option<VmArea> {
+    vmalloc::try_find(addr)
+        .filter(|vma| vma.kind == VmaKind::Stack)
+        .filter(|vma| addr < vma.start + GUARD_PAGE_SIZE)
+}
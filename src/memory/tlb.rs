@@ -0,0 +1,88 @@
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::structures::paging::mapper::MapperFlush;
+use x86_64::structures::paging::{Page, PageSize};
+use x86_64::VirtAddr;
+use crate::internals::ipi;
+
+// past this many pages one full flush is cheaper than flushing them one at a time
+const BATCH_PAGES: usize = 32;
+
+/// pages whose mappings were taken away or made stricter, flushed from every cpu's tlb in one go.
+/// collect into it while `MEM_MAPPER` is held and flush after letting go: the other cpus have to
+/// answer the shootdown, and one of them may be spinning on the mapper with interrupts off.
+/// anything freed by the change (frames, virtual ranges) can only be reused after the flush
+pub struct TlbBatch {
+    pages: [VirtAddr; BATCH_PAGES],
+    len: usize,
+    // more pages than fit, so everything gets flushed instead
+    overflowed: bool,
+}
+
+impl TlbBatch {
+    pub const fn new() -> TlbBatch {
+        TlbBatch {
+            pages: [VirtAddr::zero(); BATCH_PAGES],
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    /// takes the flush a page table change handed back, instead of flushing it on this cpu alone
+    pub fn add<S: PageSize>(&mut self, page: Page<S>, flush: MapperFlush<S>) {
+        flush.ignore();
+        if self.len == BATCH_PAGES {
+            self.overflowed = true;
+        } else {
+            self.pages[self.len] = page.start_address();
+            self.len += 1;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0 && !self.overflowed
+    }
+
+    /// flushes everything added so far on every cpu, and waits until they all have
+    pub fn flush(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+        let batch = &*self;
+        ipi::on_each_cpu(|| batch.flush_local());
+        self.len = 0;
+        self.overflowed = false;
+    }
+
+    fn flush_local(&self) {
+        if self.overflowed {
+            flush_everything();
+        } else {
+            for addr in self.pages[..self.len].iter() {
+                tlb::flush(*addr);
+            }
+        }
+    }
+}
+
+// a backstop, callers should flush themselves once the mapper is unlocked
+impl Drop for TlbBatch {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// flushes this cpu's whole tlb, global kernel pages included, which reloading cr3 would keep
+pub fn flush_everything() {
+    interrupts::without_interrupts(|| {
+        let cr4 = Cr4::read();
+        if cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+            unsafe {
+                Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+                Cr4::write(cr4);
+            }
+        } else {
+            tlb::flush_all();
+        }
+    });
+}
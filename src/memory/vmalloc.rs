@@ -0,0 +1,178 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::VirtAddr;
+use crate::{debug, lock_class, println};
+use crate::memory::{FRAME_ALLOC, MEM_MAPPER, PageSize};
+use crate::memory::tlb::TlbBatch;
+use crate::sync::SpinLock;
+
+/// kernel virtual window handed out by this module, for anything that isn't the heap or the direct map
+pub const VMALLOC_START: u64 = 0xffff_e000_0000_0000;
+pub const VMALLOC_SIZE: u64 = 0x0000_0100_0000_0000; // 1 TiB
+
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VmaKind {
+    /// backed by frames we allocated ourselves, freed along with the region
+    Vmalloc,
+    /// device memory, the frames aren't ours
+    Mmio,
+    /// kernel stack, lowest page is a guard and stays unmapped
+    Stack,
+    /// reserved, mapped by whoever asked for it
+    Reserved,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct VmArea {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub kind: VmaKind,
+}
+
+impl VmArea {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page<PageSize>> {
+        let start_page = Page::containing_address(self.start);
+        let end_page = Page::containing_address(self.end() - 1u64);
+        Page::range_inclusive(start_page, end_page)
+    }
+}
+
+lazy_static! {
+    // keyed by start address, regions never overlap
+    static ref VMAS: SpinLock<BTreeMap<u64, VmArea>> = SpinLock::named("VMAS", lock_class!(), BTreeMap::new());
+}
+
+/// finds a free, page aligned virtual range of at least `size` bytes and records it, without mapping anything
+pub fn reserve(size: u64, kind: VmaKind) -> Option<VmArea> {
+    let size = ((size + PAGE_SIZE - 1) / PAGE_SIZE) * PAGE_SIZE;
+    if size == 0 {
+        return None;
+    }
+    let mut vmas = VMAS.lock();
+
+    // first fit, walking the gaps between regions in address order
+    let mut candidate = VMALLOC_START;
+    for vma in vmas.values() {
+        if vma.start.as_u64() - candidate >= size {
+            break;
+        }
+        candidate = vma.end().as_u64();
+    }
+    if candidate + size > VMALLOC_START + VMALLOC_SIZE {
+        debug!("vmalloc: no room for {} bytes", size);
+        return None;
+    }
+
+    let vma = VmArea {
+        start: VirtAddr::new(candidate),
+        size,
+        kind,
+    };
+    vmas.insert(candidate, vma);
+    Some(vma)
+}
+
+/// forgets the region starting at `start`, the caller must already have unmapped it
+pub fn release(start: VirtAddr) -> Option<VmArea> {
+    VMAS.lock().remove(&start.as_u64())
+}
+
+/// looks up the region containing `addr`
+pub fn find(addr: VirtAddr) -> Option<VmArea> {
+    let vmas = VMAS.lock();
+    find_in(&vmas, addr)
+}
+
+/// like `find`, but returns None instead of spinning if the vma list is locked (for fault handlers)
+pub fn try_find(addr: VirtAddr) -> Option<VmArea> {
+    let vmas = VMAS.try_lock()?;
+    find_in(&vmas, addr)
+}
+
+fn find_in(vmas: &BTreeMap<u64, VmArea>, addr: VirtAddr) -> Option<VmArea> {
+    vmas.range(..=addr.as_u64()).next_back()
+        .map(|(_, vma)| *vma)
+        .filter(|vma| addr < vma.end())
+}
+
+/// allocates `size` bytes of virtually contiguous memory backed by whatever frames are free
+pub fn vmalloc(size: u64) -> Result<VirtAddr, MapToError<PageSize>> {
+    let vma = reserve(size, VmaKind::Vmalloc).ok_or(MapToError::FrameAllocationFailed)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    if let Err(e) = map_fresh_pages(&vma, flags) {
+        vfree(vma.start);
+        return Err(e);
+    }
+    Ok(vma.start)
+}
+
+/// unmaps a region returned by `vmalloc` (or a stack) and gives its frames back
+pub fn vfree(start: VirtAddr) {
+    let vma = match VMAS.lock().get(&start.as_u64()) {
+        Some(vma) => *vma,
+        None => {
+            debug!("vfree: {:#x} is not the start of a region", start.as_u64());
+            return;
+        }
+    };
+    // the frames can't go back until no cpu can reach them through a stale tlb entry. room for them
+    // is made up front, the heap can't grow while we hold the mapper
+    let mut frames = Vec::with_capacity(vma.pages().count());
+    let mut batch = TlbBatch::new();
+    {
+        let mut mapper = MEM_MAPPER.lock();
+        let mapper = mapper.as_mut().unwrap();
+        for page in vma.pages() {
+            // partially mapped regions (failed vmalloc, stack guard pages) are expected to have holes
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                batch.add(page, flush);
+                frames.push(frame);
+            }
+        }
+    }
+    batch.flush();
+    // nor can the range, or a new mapping there could be shadowed by an old entry
+    release(start);
+    if vma.kind == VmaKind::Vmalloc || vma.kind == VmaKind::Stack {
+        let mut frame_alloc = FRAME_ALLOC.lock();
+        let frame_alloc = frame_alloc.as_mut().unwrap();
+        for frame in frames {
+            unsafe { frame_alloc.deallocate_frame(frame) };
+        }
+    }
+}
+
+/// backs every page of `vma` with a freshly allocated frame
+pub fn map_fresh_pages(vma: &VmArea, flags: PageTableFlags) -> Result<(), MapToError<PageSize>> {
+    let mut mapper = MEM_MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    let mut frame_alloc = FRAME_ALLOC.lock();
+    let frame_alloc = frame_alloc.as_mut().unwrap();
+    for page in vma.pages() {
+        let frame = frame_alloc.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        match unsafe { mapper.map_to(page, frame, flags, frame_alloc) } {
+            Ok(flush) => flush.flush(),
+            Err(e) => {
+                unsafe { frame_alloc.deallocate_frame(frame) };
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn print_vmas() {
+    println!("---kernel virtual memory areas---");
+    for vma in VMAS.lock().values() {
+        println!("{:#x} - {:#x} ({} KiB) {:?}", vma.start.as_u64(), vma.end().as_u64(), vma.size / 1024, vma.kind);
+    }
+}
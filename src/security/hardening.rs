@@ -0,0 +1,152 @@
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, Translate};
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::VirtAddr;
+use crate::{debug, println};
+use crate::memory::{MEM_MAPPER, PageSize};
+use crate::memory::tlb::TlbBatch;
+
+// provided by arch/x86_64/linker.ld
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __bss_end: u8;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Protections {
+    pub nx: bool,
+    pub smep: bool,
+    pub smap: bool,
+    pub umip: bool,
+}
+
+pub fn detect_protections() -> Protections {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    let nx = max_extended_leaf >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 20) != 0;
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    let (smep, smap, umip) = if max_leaf >= 7 {
+        let leaf7 = unsafe { __cpuid_count(7, 0) };
+        (leaf7.ebx & (1 << 7) != 0, leaf7.ebx & (1 << 20) != 0, leaf7.ecx & (1 << 2) != 0)
+    } else {
+        (false, false, false)
+    };
+    Protections { nx, smep, smap, umip }
+}
+
+fn section(start: &u8, end: &u8) -> (VirtAddr, VirtAddr) {
+    (VirtAddr::from_ptr(start), VirtAddr::from_ptr(end))
+}
+
+/// enables every protection the cpu supports, then remaps the kernel image so that
+/// nothing is both writable and executable. returns what ended up enabled
+pub fn harden_kernel() -> Protections {
+    let protections = detect_protections();
+
+    enable_paging_protections(&protections);
+
+    let (text_start, text_end, rodata_start, rodata_end, data_start, data_end) = unsafe {
+        let (text_start, text_end) = section(&__text_start, &__text_end);
+        let (rodata_start, rodata_end) = section(&__rodata_start, &__rodata_end);
+        let (data_start, data_end) = section(&__data_start, &__bss_end);
+        (text_start, text_end, rodata_start, rodata_end, data_start, data_end)
+    };
+    let nx = if protections.nx { PageTableFlags::NO_EXECUTE } else { PageTableFlags::empty() };
+    remap_section(".text", text_start, text_end, PageTableFlags::PRESENT);
+    remap_section(".rodata", rodata_start, rodata_end, PageTableFlags::PRESENT | nx);
+    remap_section(".data/.bss", data_start, data_end, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | nx);
+
+    enable_cr4_protections(&protections);
+
+    protections
+}
+
+/// turns on what `harden_kernel` enabled on the boot cpu for the cpu we're running on.
+/// the page tables are shared, so the remapping doesn't need doing again
+pub fn enable_cpu_protections(protections: &Protections) {
+    enable_paging_protections(protections);
+    enable_cr4_protections(protections);
+}
+
+fn enable_paging_protections(protections: &Protections) {
+    unsafe {
+        // without WP the kernel can write straight through read-only mappings
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+        if protections.nx {
+            Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+    }
+}
+
+fn enable_cr4_protections(protections: &Protections) {
+    unsafe {
+        Cr4::update(|flags| {
+            if protections.smep {
+                flags.insert(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION);
+            }
+            if protections.smap {
+                flags.insert(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION);
+            }
+            if protections.umip {
+                flags.insert(Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION);
+            }
+        });
+    }
+}
+
+fn remap_section(name: &str, start: VirtAddr, end: VirtAddr, flags: PageTableFlags) {
+    if end <= start {
+        return;
+    }
+    debug!("hardening: {} {:#x} - {:#x} -> {:?}", name, start.as_u64(), end.as_u64(), flags);
+    // other cpus may still have the old permissions cached
+    let mut batch = TlbBatch::new();
+    {
+        let mut mapper = MEM_MAPPER.lock();
+        let mapper = mapper.as_mut().unwrap();
+        let start_page: Page<PageSize> = Page::containing_address(start);
+        let end_page: Page<PageSize> = Page::containing_address(end - 1u64);
+        for page in Page::range_inclusive(start_page, end_page) {
+            // keep whatever else the bootloader set (global, accessed, dirty), only touch the permission bits
+            let existing = match mapper.translate(page.start_address()) {
+                TranslateResult::Mapped { flags, .. } => flags,
+                _ => {
+                    println!("hardening: {} page {:?} is not mapped", name, page);
+                    continue;
+                }
+            };
+            let new_flags = (existing - (PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)) | flags;
+            match unsafe { mapper.update_flags(page, new_flags) } {
+                Ok(flush) => batch.add(page, flush),
+                Err(e) => println!("hardening: failed to remap {} page {:?}: {:?}", name, page, e),
+            }
+        }
+
+        // make sure it actually stuck
+        for page in Page::range_inclusive(start_page, end_page) {
+            if let TranslateResult::Mapped { flags: actual, .. } = mapper.translate(page.start_address()) {
+                let writable = actual.contains(PageTableFlags::WRITABLE);
+                let executable = !actual.contains(PageTableFlags::NO_EXECUTE);
+                if writable != flags.contains(PageTableFlags::WRITABLE) || executable != !flags.contains(PageTableFlags::NO_EXECUTE) {
+                    println!("hardening: {} page {:?} has unexpected flags {:?}", name, page, actual);
+                }
+                if writable && executable {
+                    println!("hardening: {} page {:?} is writable and executable!", name, page);
+                }
+            }
+        }
+    }
+    batch.flush();
+}
+
+pub fn print_protections(protections: &Protections) {
+    let on_off = |enabled: bool| if enabled { "on" } else { "off" };
+    // W^X relies on NX, without it everything readable is executable
+    println!("protections: W^X {}, NX {}, SMEP {}, SMAP {}, UMIP {}",
+        on_off(protections.nx), on_off(protections.nx), on_off(protections.smep), on_off(protections.smap), on_off(protections.umip));
+}
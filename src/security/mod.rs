@@ -1 +1,3 @@
-pub mod stack_smashing_protection;
\ No newline at end of file
+pub mod stack_smashing_protection;
+pub mod hardening;
+pub mod random;
\ No newline at end of file
@@ -0,0 +1,247 @@
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdrand64_step, _rdseed64_step, _rdtsc};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::debug;
+
+// reseed the generator from the pool after handing out this many bytes
+const RESEED_INTERVAL: usize = 1024 * 1024;
+// ...or as soon as this many new samples have been mixed into the pool
+const RESEED_SAMPLES: usize = 256;
+
+static POOL: Mutex<EntropyPool> = Mutex::new(EntropyPool::new());
+static RNG: Mutex<ChaChaRng> = Mutex::new(ChaChaRng::new());
+
+pub fn check_rdrand_compat() -> bool {
+    unsafe { __cpuid(1) }.ecx & (1 << 30) != 0
+}
+
+pub fn check_rdseed_compat() -> bool {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    max_leaf >= 7 && unsafe { __cpuid_count(7, 0) }.ebx & (1 << 18) != 0
+}
+
+#[target_feature(enable = "rdseed")]
+unsafe fn rdseed() -> Option<u64> {
+    let mut value = 0;
+    // rdseed can run dry for a moment, retry a few times
+    for _ in 0..16 {
+        if _rdseed64_step(&mut value) == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand() -> Option<u64> {
+    let mut value = 0;
+    for _ in 0..16 {
+        if _rdrand64_step(&mut value) == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// splitmix64 finaliser, spreads the few bits of jitter we get over the whole word
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// last resort: the low bits of the tsc wobble a little between reads
+fn tsc_jitter() -> u64 {
+    let mut acc = 0u64;
+    for i in 0..64 {
+        let before = unsafe { _rdtsc() };
+        // something with a slightly unpredictable duration
+        let _ = unsafe { __cpuid(0) };
+        let after = unsafe { _rdtsc() };
+        acc = mix(acc ^ after.wrapping_sub(before).rotate_left(i) ^ after);
+    }
+    acc
+}
+
+/// one word straight from the best hardware source available, without going through the pool
+pub fn hardware_random() -> u64 {
+    unsafe {
+        if check_rdseed_compat() {
+            rdseed()
+        } else {
+            None
+        }.or_else(|| if check_rdrand_compat() { rdrand() } else { None })
+    }.unwrap_or_else(tsc_jitter)
+}
+
+struct EntropyPool {
+    words: [u64; 8],
+    index: usize,
+    // samples mixed in since the last reseed
+    samples: usize,
+}
+
+impl EntropyPool {
+    const fn new() -> Self {
+        Self {
+            words: [0; 8],
+            index: 0,
+            samples: 0,
+        }
+    }
+
+    fn add(&mut self, sample: u64) {
+        let i = self.index;
+        self.words[i] = mix(self.words[i].rotate_left(23) ^ sample ^ self.words[(i + 3) % 8]);
+        self.index = (i + 1) % 8;
+        self.samples += 1;
+    }
+
+    fn take(&mut self) -> [u64; 8] {
+        self.samples = 0;
+        self.words
+    }
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]); state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]); state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]); state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]); state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+fn chacha20_block(key: &[u32; 8], counter: u64, nonce: u64) -> [u32; 16] {
+    let mut input = [0u32; 16];
+    // "expand 32-byte k"
+    input[0..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+    input[14] = nonce as u32;
+    input[15] = (nonce >> 32) as u32;
+
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (out, inp) in state.iter_mut().zip(input.iter()) {
+        *out = out.wrapping_add(*inp);
+    }
+    state
+}
+
+/// chacha20 keystream generator with fast key erasure: after every request the key is replaced
+/// with fresh keystream, so a later compromise can't be used to recover earlier output
+struct ChaChaRng {
+    key: [u32; 8],
+    counter: u64,
+    nonce: u64,
+    since_reseed: usize,
+    seeded: bool,
+}
+
+impl ChaChaRng {
+    const fn new() -> Self {
+        Self {
+            key: [0; 8],
+            counter: 0,
+            nonce: 0,
+            since_reseed: 0,
+            seeded: false,
+        }
+    }
+
+    fn reseed(&mut self, seed: &[u64; 8]) {
+        // fold the seed into the current key instead of replacing it, so a weak seed can't make things worse
+        for (i, word) in seed.iter().enumerate() {
+            self.key[(i * 2) % 8] ^= *word as u32;
+            self.key[(i * 2 + 1) % 8] ^= (*word >> 32) as u32;
+        }
+        self.nonce = self.nonce.wrapping_add(1);
+        self.rekey();
+        self.since_reseed = 0;
+        self.seeded = true;
+    }
+
+    fn rekey(&mut self) {
+        let block = chacha20_block(&self.key, self.counter, self.nonce);
+        self.counter = self.counter.wrapping_add(1);
+        self.key.copy_from_slice(&block[0..8]);
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(64) {
+            let block = chacha20_block(&self.key, self.counter, self.nonce);
+            self.counter = self.counter.wrapping_add(1);
+            for (i, byte) in chunk.iter_mut().enumerate() {
+                *byte = (block[i / 4] >> ((i % 4) * 8)) as u8;
+            }
+        }
+        self.since_reseed += dest.len();
+        self.rekey();
+    }
+}
+
+/// seeds the generator from hardware, should run before anything wants random numbers
+pub fn init() {
+    let mut seed = [0u64; 8];
+    for word in seed.iter_mut() {
+        *word = hardware_random();
+    }
+    debug!("random: rdseed {}, rdrand {}", check_rdseed_compat(), check_rdrand_compat());
+    without_interrupts(|| RNG.lock().reseed(&seed));
+}
+
+/// mixes a sample into the entropy pool. never blocks, the sample is simply dropped if the pool
+/// is busy, so this is safe to call from interrupt handlers
+pub fn add_entropy(sample: u64) {
+    if let Some(mut pool) = POOL.try_lock() {
+        pool.add(sample);
+    }
+}
+
+/// feeds the current tsc (and which interrupt fired) into the pool, called from irq handlers
+pub fn add_interrupt_timing(vector: u8) {
+    let tsc = unsafe { _rdtsc() };
+    add_entropy(tsc ^ ((vector as u64) << 56));
+}
+
+pub fn fill_bytes(dest: &mut [u8]) {
+    without_interrupts(|| {
+        let mut rng = RNG.lock();
+        let reseed = {
+            let mut pool = POOL.lock();
+            if !rng.seeded || rng.since_reseed >= RESEED_INTERVAL || pool.samples >= RESEED_SAMPLES {
+                let mut seed = pool.take();
+                // hardware randomness goes in too, when we have it
+                seed[0] ^= hardware_random();
+                Some(seed)
+            } else {
+                None
+            }
+        };
+        if let Some(seed) = reseed {
+            rng.reseed(&seed);
+        }
+        rng.fill_bytes(dest);
+    });
+}
+
+pub fn next_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    fill_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+pub fn next_u32() -> u32 {
+    let mut bytes = [0u8; 4];
+    fill_bytes(&mut bytes);
+    u32::from_le_bytes(bytes)
+}
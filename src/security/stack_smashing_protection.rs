@@ -1,8 +1,43 @@
-pub const STACK_CHK_GUARD: usize = usize::MAX;
+use core::arch::asm;
+use core::ptr;
+use crate::security::random;
 
-pub const STACK_CHK_PTR: *const usize = &STACK_CHK_GUARD;
+/// the canary llvm compares against when built with `-Z stack-protector`.
+/// starts out as an arbitrary constant and gets replaced by `init_stack_guard` as early as possible
+#[no_mangle]
+pub static mut __stack_chk_guard: usize = 0x595e_9fbd_94fd_a766;
 
-pub extern "C" fn __stack_chk_fail() -> ! {
-    panic!("stack fucking or uh smashing detected!");
-    loop {}
-}
\ No newline at end of file
+/// called by instrumented functions when their canary doesn't match.
+/// naked so we can grab the return address (which points into the function that got smashed)
+/// before anything else touches the stack
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn __stack_chk_fail() -> ! {
+    asm!(
+        "mov rdi, [rsp]",
+        // keep the stack 16 byte aligned for the call
+        "sub rsp, 8",
+        "call {report}",
+        report = sym stack_chk_fail_report,
+        options(noreturn),
+    );
+}
+
+extern "C" fn stack_chk_fail_report(return_address: u64) -> ! {
+    panic!("stack fucking or uh smashing detected! canary check failed in the function containing {:#x}", return_address);
+}
+
+/// replaces the canary with a random one. this has to be inlined into a function that never returns
+/// (kernel_main), otherwise the caller's saved canary would no longer match on the way out
+#[inline(always)]
+pub fn init_stack_guard(seed: u64) {
+    unsafe {
+        ptr::write_volatile(ptr::addr_of_mut!(__stack_chk_guard), seed as usize);
+    }
+}
+
+/// gets a random value for the canary, `random::init` has to have run already
+pub fn random_canary() -> u64 {
+    // a zero byte at the bottom stops string functions from leaking or overwriting the canary
+    random::next_u64() & !0xff
+}
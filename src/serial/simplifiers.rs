@@ -1,11 +1,11 @@
 use lazy_static::lazy_static;
 use pc_keyboard::{Keyboard, layouts, ScancodeSet1, HandleControl};
 use pc_keyboard::DecodedKey::Unicode;
-use spin::Mutex;
-use crate::print;
+use crate::{lock_class, print};
+use crate::sync::SpinLock;
 
 lazy_static!{
-    static ref KBD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(Keyboard::new(HandleControl::MapLettersToUnicode));
+    static ref KBD: SpinLock<Keyboard<layouts::Us104Key, ScancodeSet1>> = SpinLock::named("KBD", lock_class!(), Keyboard::new(HandleControl::MapLettersToUnicode));
 }
 
 pub fn handle_scancode(scancode: u8) {
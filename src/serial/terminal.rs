@@ -1,25 +1,27 @@
 use core::fmt;
 use core::ops::Deref;
 use lazy_static::lazy_static;
-use spin::Mutex;
-use x86_64::instructions::interrupts::without_interrupts;
+use crate::lock_class;
+use crate::sync::SpinLock;
 use crate::serial::Port;
 
+// spinlocks that keep interrupts off, so printing from an interrupt handler can't deadlock
+// against the code it interrupted
 pub struct SerialTerminal {
-    pub port: Mutex<Option<Port>>,
-    pub writer: Mutex<SerialTerminalWriter>,
+    pub port: SpinLock<Option<Port>>,
+    pub writer: SpinLock<SerialTerminalWriter>,
 }
 
 pub struct SerialTerminalWriter {
-    pub port: Mutex<Option<Port>>,
+    pub port: SpinLock<Option<Port>>,
 }
 
 lazy_static! {
     pub static ref ST: SerialTerminal = {
         let serial_terminal: SerialTerminal = SerialTerminal {
-            port: Mutex::new(None),
-            writer: Mutex::new(SerialTerminalWriter {
-                port: Mutex::new(None),
+            port: SpinLock::named("ST.port", lock_class!(), None),
+            writer: SpinLock::named("ST.writer", lock_class!(), SerialTerminalWriter {
+                port: SpinLock::named("ST.writer.port", lock_class!(), None),
             }),
         };
         serial_terminal
@@ -33,30 +35,24 @@ impl SerialTerminal {
     }
 
     pub fn log(&self, message: &str) {
-        without_interrupts(|| {
-            if let Some(port) = self.port.lock().deref() {
-                port.transmit_string(message);
-            }
-        });
+        if let Some(port) = self.port.lock().deref() {
+            port.transmit_string(message);
+        }
     }
 
     pub fn logln(&self, message: &str) {
-        without_interrupts(|| {
-            if let Some(port) = self.port.lock().deref() {
-                port.transmit_string(message);
-                port.transmit_string("\r\n");
-            }
-        });
+        if let Some(port) = self.port.lock().deref() {
+            port.transmit_string(message);
+            port.transmit_string("\r\n");
+        }
     }
 }
 
 impl fmt::Write for SerialTerminalWriter {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        without_interrupts(|| {
-            if let Some(port) = self.port.lock().deref() {
-                port.transmit_string(s);
-            }
-        });
+        if let Some(port) = self.port.lock().deref() {
+            port.transmit_string(s);
+        }
         Ok(())
     }
 }
\ No newline at end of file
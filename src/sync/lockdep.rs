@@ -0,0 +1,366 @@
+//! lock dependency checker. locks are sorted into classes by their `LockClassKey`, and whenever
+//! one is taken while others are held we remember the order of their classes. taking two classes in both orders (even on different
+//! cpus, at different times) is reported before it gets the chance to deadlock for real, and so
+//! is sleeping with a spinlock held or in an interrupt handler.
+//!
+//! everything lives in fixed size tables, the allocator is itself a spinlock. after the first
+//! report the checker turns itself off, the tables can't be trusted anymore. reports are written
+//! to a buffer and only printed once the tables are let go of: printing takes the serial lock,
+//! and whoever holds that may be waiting on the tables
+
+use core::fmt::{self, Write};
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+use crate::internals::percpu;
+use crate::sync::LockClassKey;
+use crate::{percpu, print};
+
+const MAX_CLASSES: usize = 128;
+const MAX_DEPENDENCIES: usize = 1024;
+const MAX_HELD: usize = 32;
+const REPORT_SIZE: usize = 4096;
+
+type Site = &'static Location<'static>;
+// no dependency, in `Graph::path`
+const NOWHERE: u16 = u16::MAX;
+// no report waiting to be printed, in `REPORTER`
+const NO_REPORTER: usize = usize::MAX;
+
+#[derive(Clone, Copy)]
+struct Class {
+    // address of its `LockClassKey`
+    key: usize,
+    name: &'static str,
+    // first place it was taken in an interrupt handler
+    irq_site: Option<Site>,
+}
+
+// `from` was held at `from_site` while `to` was taken at `to_site`
+#[derive(Clone, Copy)]
+struct Dependency {
+    from: usize,
+    to: usize,
+    from_site: Site,
+    to_site: Site,
+}
+
+#[derive(Clone, Copy)]
+struct Held {
+    class: usize,
+    site: Site,
+}
+
+struct Graph {
+    classes: [Option<Class>; MAX_CLASSES],
+    class_count: usize,
+    dependencies: [Option<Dependency>; MAX_DEPENDENCIES],
+    dependency_count: usize,
+}
+
+struct HeldLocks {
+    locks: [Option<Held>; MAX_HELD],
+    len: usize,
+}
+
+// the text of a report, whatever doesn't fit is dropped
+struct Report {
+    text: [u8; REPORT_SIZE],
+    len: usize,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+// raw spinlocks, a SpinLock in here would end up checking itself
+static GRAPH: spin::Mutex<Graph> = spin::Mutex::new(Graph {
+    classes: [None; MAX_CLASSES],
+    class_count: 0,
+    dependencies: [None; MAX_DEPENDENCIES],
+    dependency_count: 0,
+});
+static REPORT: spin::Mutex<Report> = spin::Mutex::new(Report { text: [0; REPORT_SIZE], len: 0 });
+// the cpu that wrote the report and has yet to print it
+static REPORTER: AtomicUsize = AtomicUsize::new(NO_REPORTER);
+
+percpu! {
+    static HELD: spin::Mutex<HeldLocks> = spin::Mutex::new(HeldLocks { locks: [None; MAX_HELD], len: 0 });
+    // set while this cpu is inside the checker, so locks it takes to print a report are left alone
+    static IN_LOCKDEP: AtomicBool = AtomicBool::new(false);
+}
+
+impl Graph {
+    fn class(&self, index: usize) -> Class {
+        self.classes[index].unwrap()
+    }
+
+    fn find_or_add_class(&mut self, key: usize, name: &'static str) -> Option<usize> {
+        if let Some(index) = self.classes[..self.class_count].iter().position(|class| class.unwrap().key == key) {
+            return Some(index);
+        }
+        if self.class_count == MAX_CLASSES {
+            return None;
+        }
+        self.classes[self.class_count] = Some(Class { key, name, irq_site: None });
+        self.class_count += 1;
+        Some(self.class_count - 1)
+    }
+
+    fn has_dependency(&self, from: usize, to: usize) -> bool {
+        self.dependencies[..self.dependency_count].iter()
+            .any(|dependency| dependency.map_or(false, |d| d.from == from && d.to == to))
+    }
+
+    // breadth first search from `from` to `to`. returns, for every class reached, the
+    // dependency it was reached through, so the chain can be walked back from `to`.
+    // kept small, this runs on whatever stack the lock was taken on
+    fn path(&self, from: usize, to: usize) -> Option<[u16; MAX_CLASSES]> {
+        let mut via = [NOWHERE; MAX_CLASSES];
+        let mut visited = [false; MAX_CLASSES];
+        let mut queue = [0u16; MAX_CLASSES];
+        let (mut head, mut tail) = (0, 0);
+        queue[tail] = from as u16;
+        tail += 1;
+        visited[from] = true;
+        while head < tail {
+            let class = queue[head] as usize;
+            head += 1;
+            if class == to {
+                return Some(via);
+            }
+            for (index, dependency) in self.dependencies[..self.dependency_count].iter().enumerate() {
+                let dependency = dependency.unwrap();
+                if dependency.from == class && !visited[dependency.to] {
+                    visited[dependency.to] = true;
+                    via[dependency.to] = index as u16;
+                    queue[tail] = dependency.to as u16;
+                    tail += 1;
+                }
+            }
+        }
+        None
+    }
+}
+
+impl HeldLocks {
+    fn held(&self) -> impl Iterator<Item = Held> + '_ {
+        self.locks[..self.len].iter().map(|held| held.unwrap())
+    }
+}
+
+impl Write for Report {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.len + s.len() <= REPORT_SIZE {
+            self.text[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+            self.len += s.len();
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.name.is_empty() {
+            write!(f, "lock class {:#x}", self.key)
+        } else {
+            write!(f, "{}", self.name)
+        }
+    }
+}
+
+// runs `f` unless the checker is off, this cpu is already in it, or per-cpu data isn't up yet
+fn enter<R>(f: impl FnOnce(usize) -> Option<R>) -> Option<R> {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return None;
+    }
+    interrupts::without_interrupts(|| {
+        let cpu = percpu::try_this_cpu()?;
+        if IN_LOCKDEP.get_for(cpu.index).swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        let result = f(cpu.index);
+        // `f` has let go of the tables by now
+        if REPORTER.load(Ordering::SeqCst) == cpu.index {
+            print_report();
+        }
+        IN_LOCKDEP.get_for(cpu.index).store(false, Ordering::SeqCst);
+        result
+    })
+}
+
+// turns the checker off. the first cpu to get here has `write` say what went wrong, and prints it
+// on its way out of `enter`
+fn report(cpu: usize, write: impl FnOnce(&mut Report) -> fmt::Result) {
+    if !ENABLED.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    let mut report = REPORT.lock();
+    let _ = write(&mut report);
+    let _ = writeln!(report, "lockdep: turning off the checker");
+    REPORTER.store(cpu, Ordering::SeqCst);
+}
+
+fn print_report() {
+    let report = REPORT.lock();
+    print!("{}", core::str::from_utf8(&report.text[..report.len]).unwrap_or("lockdep: garbled report\n"));
+    REPORTER.store(NO_REPORTER, Ordering::SeqCst);
+}
+
+fn write_held(report: &mut Report, graph: &Graph, held: &HeldLocks) -> fmt::Result {
+    writeln!(report, "lockdep: locks held by this cpu:")?;
+    for lock in held.held() {
+        writeln!(report, "  {} taken at {}", graph.class(lock.class), lock.site)?;
+    }
+    Ok(())
+}
+
+/// called by `SpinLock` before it starts spinning. `try_lock` passes `trylock`, those can't wait
+/// on anyone so they don't add ordering
+pub fn lock_acquire(key: &'static LockClassKey, name: &'static str, site: Site, trylock: bool) {
+    enter(|cpu| {
+        let mut graph = GRAPH.lock();
+        let mut held = HELD.get_for(cpu).lock();
+        let class = match graph.find_or_add_class(key as *const LockClassKey as usize, name) {
+            Some(class) => class,
+            None => {
+                report(cpu, |report| writeln!(report, "lockdep: more than {} lock classes", MAX_CLASSES));
+                return None;
+            }
+        };
+        if percpu::in_interrupt() && graph.class(class).irq_site.is_none() {
+            graph.classes[class].as_mut().unwrap().irq_site = Some(site);
+        }
+
+        if !trylock {
+            for lock in held.held().collect::<HeldArray>().iter() {
+                if lock.class == class {
+                    report(cpu, |report| {
+                        writeln!(report, "lockdep: possible recursive locking of {} at {}", graph.class(class), site)?;
+                        writeln!(report, "lockdep: a lock of that class was already taken at {}", lock.site)?;
+                        write_held(report, &graph, &held)
+                    });
+                    return None;
+                }
+                if graph.has_dependency(lock.class, class) {
+                    continue;
+                }
+                // the new order closes a loop if there's already a way back from `class` to `lock`
+                if let Some(via) = graph.path(class, lock.class) {
+                    report(cpu, |report| write_cycle(report, &graph, &held, lock, class, site, &via));
+                    return None;
+                }
+                if graph.dependency_count == MAX_DEPENDENCIES {
+                    report(cpu, |report| writeln!(report, "lockdep: more than {} lock dependencies", MAX_DEPENDENCIES));
+                    return None;
+                }
+                let count = graph.dependency_count;
+                graph.dependencies[count] = Some(Dependency { from: lock.class, to: class, from_site: lock.site, to_site: site });
+                graph.dependency_count += 1;
+            }
+        }
+
+        if held.len == MAX_HELD {
+            report(cpu, |report| writeln!(report, "lockdep: more than {} locks held at {}", MAX_HELD, site));
+            return None;
+        }
+        let len = held.len;
+        held.locks[len] = Some(Held { class, site });
+        held.len += 1;
+        Some(())
+    });
+}
+
+/// called by `SpinLock` just before it's released
+pub fn lock_release(key: &'static LockClassKey) {
+    let key = key as *const LockClassKey as usize;
+    enter(|cpu| {
+        let graph = GRAPH.lock();
+        let mut held = HELD.get_for(cpu).lock();
+        let class = graph.classes[..graph.class_count].iter().position(|class| class.unwrap().key == key)?;
+        // locks don't have to be released in the order they were taken
+        let position = held.held().collect::<HeldArray>().iter().rposition(|lock| lock.class == class)?;
+        let lock = held.locks[position].unwrap();
+        if interrupts::are_enabled() {
+            // SpinLock turned them off, so someone turned them back on while holding it
+            report(cpu, |report| {
+                writeln!(report, "lockdep: interrupts enabled while holding {} (taken at {})", graph.class(class), lock.site)?;
+                if let Some(irq_site) = graph.class(class).irq_site {
+                    writeln!(report, "lockdep: which is also taken in interrupt context at {}, that can deadlock", irq_site)?;
+                }
+                Ok(())
+            });
+            return None;
+        }
+        let len = held.len;
+        held.locks.copy_within(position + 1..len, position);
+        held.locks[len - 1] = None;
+        held.len -= 1;
+        Some(())
+    });
+}
+
+/// called by anything that can put the thread to sleep, right before it might
+#[track_caller]
+pub fn might_sleep() {
+    let site = Location::caller();
+    enter(|cpu| {
+        let graph = GRAPH.lock();
+        let held = HELD.get_for(cpu).lock();
+        let problem = if percpu::in_interrupt() {
+            "in interrupt context"
+        } else if held.len != 0 {
+            "with spinlocks held"
+        } else {
+            return None;
+        };
+        report(cpu, |report| {
+            writeln!(report, "lockdep: sleeping {} at {}", problem, site)?;
+            write_held(report, &graph, &held)
+        });
+        Some(())
+    });
+}
+
+fn write_cycle(report: &mut Report, graph: &Graph, held: &HeldLocks, lock: &Held, class: usize, site: Site, via: &[u16; MAX_CLASSES]) -> fmt::Result {
+    writeln!(report, "lockdep: possible circular locking dependency")?;
+    writeln!(report, "  taking {} at {}", graph.class(class), site)?;
+    writeln!(report, "  while holding {} (taken at {})", graph.class(lock.class), lock.site)?;
+    writeln!(report, "lockdep: but the opposite order has been seen before:")?;
+    // walk back from the held lock to the one being taken, then print it the right way round
+    let mut chain = [0u16; MAX_CLASSES];
+    let mut len = 0;
+    let mut at = lock.class;
+    while via[at] != NOWHERE {
+        chain[len] = via[at];
+        len += 1;
+        at = graph.dependencies[via[at] as usize].unwrap().from;
+    }
+    for index in chain[..len].iter().rev() {
+        let dependency = graph.dependencies[*index as usize].unwrap();
+        writeln!(report, "  {} (taken at {}) -> {} (taken at {})",
+            graph.class(dependency.from), dependency.from_site,
+            graph.class(dependency.to), dependency.to_site)?;
+    }
+    write_held(report, graph, held)
+}
+
+// copy of the held locks, so the stack can be looked at while the graph changes
+struct HeldArray {
+    locks: [Option<Held>; MAX_HELD],
+    len: usize,
+}
+
+impl FromIterator<Held> for HeldArray {
+    fn from_iter<I: IntoIterator<Item = Held>>(iter: I) -> HeldArray {
+        let mut array = HeldArray { locks: [None; MAX_HELD], len: 0 };
+        for held in iter.into_iter().take(MAX_HELD) {
+            array.locks[array.len] = Some(held);
+            array.len += 1;
+        }
+        array
+    }
+}
+
+impl HeldArray {
+    fn iter(&self) -> impl DoubleEndedIterator<Item = &Held> + ExactSizeIterator {
+        self.locks[..self.len].iter().map(|held| held.as_ref().unwrap())
+    }
+}
@@ -0,0 +1,9 @@
+#[cfg(feature = "f_lockdep")]
+pub mod lockdep;
+pub mod mutex;
+pub mod semaphore;
+pub mod spinlock;
+pub mod wait_queue;
+
+pub use spinlock::{LockClassKey, SpinLock, SpinLockGuard};
+pub use wait_queue::WaitQueue;
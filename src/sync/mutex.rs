@@ -0,0 +1,119 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::sync::WaitQueue;
+
+/// a lock that puts waiting threads to sleep instead of spinning. for long critical sections in
+/// thread context, never from an interrupt handler, which can't sleep
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Mutex<T> {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<T> {
+        self.waiters.wait_until(|| self.try_acquire());
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        if self.try_acquire() {
+            Some(MutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::SeqCst)
+    }
+
+    fn try_acquire(&self) -> bool {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
+
+/// lets threads sleep until another one signals that something protected by a `Mutex` changed.
+/// like any condvar, waits can end without a notify, so check the condition in a loop
+pub struct Condvar {
+    // bumped by every notify, waiters sleep until it moves
+    generation: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar {
+            generation: AtomicU64::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// unlocks `guard`, sleeps until notified, and locks it again
+    #[track_caller]
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        // read while still holding the mutex, so a notify from whoever takes it next can't be missed
+        let generation = self.generation.load(Ordering::SeqCst);
+        drop(guard);
+        self.waiters.wait_until(|| self.generation.load(Ordering::SeqCst) != generation);
+        mutex.lock()
+    }
+
+    /// waits until `condition` is true of the protected value
+    #[track_caller]
+    pub fn wait_while<'a, T, F: FnMut(&mut T) -> bool>(&self, mut guard: MutexGuard<'a, T>, mut condition: F) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.waiters.wake_all();
+    }
+}
@@ -0,0 +1,38 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::sync::WaitQueue;
+
+/// a counter threads sleep on until it's above zero
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Semaphore {
+        Semaphore {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// takes one, sleeping until there's one to take
+    #[track_caller]
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    /// takes one if there's one to take, never sleeps, so it's fine from interrupt handlers
+    pub fn try_acquire(&self) -> bool {
+        self.count.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| count.checked_sub(1)).is_ok()
+    }
+
+    /// gives one back, waking a waiter. fine from interrupt handlers
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        self.waiters.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+}
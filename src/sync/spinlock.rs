@@ -0,0 +1,207 @@
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+#[cfg(feature = "f_debug_locks")]
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+use x86_64::instructions::interrupts;
+use crate::internals::percpu::{self, PerCpu};
+#[cfg(feature = "f_lockdep")]
+use crate::sync::lockdep;
+
+/// a spinlock that keeps interrupts off for as long as it's held, so an interrupt handler
+/// taking it can never find it held by the code it interrupted
+pub struct SpinLock<T> {
+    inner: spin::Mutex<T>,
+    // shows up in debug reports, empty for locks that didn't get a name
+    name: &'static str,
+    // what lockdep files it under
+    class: &'static LockClassKey,
+    // index + 1 of the cpu holding it, 0 when free
+    #[cfg(feature = "f_debug_locks")]
+    owner: AtomicUsize,
+}
+
+pub struct SpinLockGuard<'a, T> {
+    // dropped by hand, the lock has to be released before interrupts come back on
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    #[cfg(any(feature = "f_debug_locks", feature = "f_lockdep"))]
+    lock: &'a SpinLock<T>,
+    irq: IrqSave,
+}
+
+/// what lockdep tells lock classes apart by: every lock made with the same key is one class, so
+/// the order between, say, two wait queues' locks is checked as well. `lock_class!` makes a key
+/// for the spot it's written at, the way every lock made there should share one
+pub struct LockClassKey {
+    // statics of a zero sized type can share an address
+    _unique: u8,
+}
+
+impl LockClassKey {
+    pub const fn new() -> LockClassKey {
+        LockClassKey { _unique: 0 }
+    }
+}
+
+/// a `&'static LockClassKey` of its own for the place it's written at
+#[macro_export]
+macro_rules! lock_class {
+    () => {{
+        static KEY: $crate::sync::LockClassKey = $crate::sync::LockClassKey::new();
+        &KEY
+    }};
+}
+
+// how a guard gives interrupts back. guards can be dropped in any order, so each cpu counts the
+// spinlocks it holds and only the last one to go turns interrupts back on. before the cpu has its
+// per-cpu block interrupts are off anyway, and a guard just puts back what it found
+enum IrqSave {
+    Counted(&'static PerCpu),
+    Saved(bool),
+}
+
+fn irq_save() -> IrqSave {
+    let were_enabled = interrupts::are_enabled();
+    interrupts::disable();
+    match percpu::try_this_cpu() {
+        Some(cpu) => {
+            if cpu.spinlock_depth.fetch_add(1, Ordering::SeqCst) == 0 {
+                cpu.irqs_saved.store(were_enabled, Ordering::SeqCst);
+            }
+            IrqSave::Counted(cpu)
+        }
+        None => IrqSave::Saved(were_enabled),
+    }
+}
+
+fn irq_restore(irq: &IrqSave) {
+    let enable = match irq {
+        IrqSave::Counted(cpu) => {
+            cpu.spinlock_depth.fetch_sub(1, Ordering::SeqCst) == 1 && cpu.irqs_saved.load(Ordering::SeqCst)
+        }
+        IrqSave::Saved(were_enabled) => *were_enabled,
+    };
+    if enable {
+        interrupts::enable();
+    }
+}
+
+impl<T> SpinLock<T> {
+    /// a lock with a name for the debug checks to call it by, in lock class `class`
+    pub const fn named(name: &'static str, class: &'static LockClassKey, value: T) -> SpinLock<T> {
+        SpinLock {
+            inner: spin::Mutex::new(value),
+            name,
+            class,
+            #[cfg(feature = "f_debug_locks")]
+            owner: AtomicUsize::new(0),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        let irq = irq_save();
+        #[cfg(feature = "f_debug_locks")]
+        self.check_recursion();
+        #[cfg(feature = "f_lockdep")]
+        lockdep::lock_acquire(self.class, self.name, core::panic::Location::caller(), false);
+        let guard = self.inner.lock();
+        self.guard(guard, irq)
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        let irq = irq_save();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                #[cfg(feature = "f_lockdep")]
+                lockdep::lock_acquire(self.class, self.name, core::panic::Location::caller(), true);
+                Some(self.guard(guard, irq))
+            }
+            None => {
+                irq_restore(&irq);
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// releases the lock without a guard, for when the guard was forgotten on purpose
+    /// (or belongs to code that will never get to drop it, like a panicking one). the forgotten
+    /// guard still keeps interrupts off on the cpu that took it
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(feature = "f_debug_locks")]
+        self.owner.store(0, Ordering::SeqCst);
+        #[cfg(feature = "f_lockdep")]
+        lockdep::lock_release(self.class);
+        self.inner.force_unlock();
+    }
+
+    /// releases the lock for a guard that was forgotten earlier on this cpu, giving back its
+    /// share of the interrupt state the way dropping it would have. for locks handed across a
+    /// context switch, where the thread switched to lets go of what the one before it took
+    pub unsafe fn unlock_forgotten(&self) {
+        self.force_unlock();
+        let irq = match percpu::try_this_cpu() {
+            Some(cpu) => IrqSave::Counted(cpu),
+            // nothing recorded what the guard found, so leave interrupts off
+            None => IrqSave::Saved(false),
+        };
+        irq_restore(&irq);
+    }
+
+    fn guard<'a>(&'a self, guard: spin::MutexGuard<'a, T>, irq: IrqSave) -> SpinLockGuard<'a, T> {
+        #[cfg(feature = "f_debug_locks")]
+        if let Some(cpu) = percpu::try_this_cpu() {
+            self.owner.store(cpu.index + 1, Ordering::SeqCst);
+        }
+        SpinLockGuard {
+            guard: ManuallyDrop::new(guard),
+            #[cfg(any(feature = "f_debug_locks", feature = "f_lockdep"))]
+            lock: self,
+            irq,
+        }
+    }
+
+    // spinning on a lock this cpu already holds never ends, so complain instead. with
+    // interrupts off while it's held, that's an nmi or a fault handler, or plain recursion
+    #[cfg(feature = "f_debug_locks")]
+    #[track_caller]
+    fn check_recursion(&self) {
+        if let Some(cpu) = percpu::try_this_cpu() {
+            if self.owner.load(Ordering::SeqCst) == cpu.index + 1 {
+                panic!("spinlock recursion on {} on cpu {}", if self.name.is_empty() { "a lock" } else { self.name }, cpu.index);
+            }
+        }
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "f_debug_locks")]
+        self.lock.owner.store(0, Ordering::SeqCst);
+        #[cfg(feature = "f_lockdep")]
+        lockdep::lock_release(self.lock.class);
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+        }
+        irq_restore(&self.irq);
+    }
+}
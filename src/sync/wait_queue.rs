@@ -0,0 +1,75 @@
+use alloc::collections::VecDeque;
+use crate::lock_class;
+use crate::sync::SpinLock;
+use crate::task::{self, ThreadId};
+
+/// threads parked until some condition holds. wakers change whatever the condition looks at,
+/// then call `wake_one` or `wake_all`
+pub struct WaitQueue {
+    waiters: SpinLock<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        // every wait queue shares one class. the lock is only held across the queue operations
+        // themselves and never nested in another wait queue's, so there's no order to tell apart
+        WaitQueue {
+            waiters: SpinLock::named("wait queue", lock_class!(), VecDeque::new()),
+        }
+    }
+
+    /// blocks until `condition` returns true. it's checked with the queue locked, so a waker
+    /// that changes things and then wakes us can't slip in between the check and us parking.
+    /// before the scheduler is up there's nobody to switch to, so it spins instead
+    #[track_caller]
+    pub fn wait_until<F: FnMut() -> bool>(&self, mut condition: F) {
+        #[cfg(feature = "f_lockdep")]
+        crate::sync::lockdep::might_sleep();
+        if !task::is_running() {
+            while !condition() {
+                core::hint::spin_loop();
+            }
+            return;
+        }
+        let me = task::current_id();
+        loop {
+            {
+                let mut waiters = self.waiters.lock();
+                if condition() {
+                    // a spurious wakeup can leave us queued, and then we'd soak up someone else's
+                    waiters.retain(|waiter| *waiter != me);
+                    return;
+                }
+                if !waiters.contains(&me) {
+                    waiters.push_back(me);
+                }
+            }
+            task::block();
+        }
+    }
+
+    /// wakes the longest waiting thread, returns false if nobody was waiting
+    pub fn wake_one(&self) -> bool {
+        match self.waiters.lock().pop_front() {
+            Some(waiter) => {
+                task::wake(waiter);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// wakes every waiting thread, returns how many there were
+    pub fn wake_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        let woken = waiters.len();
+        for waiter in waiters {
+            task::wake(waiter);
+        }
+        woken
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+}
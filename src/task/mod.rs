@@ -0,0 +1,486 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
+use crate::{debug, lock_class, println};
+use crate::internals::{ipi, softirq};
+use crate::internals::percpu::{self, PerCpu, NO_THREAD};
+use crate::memory::slab::{CacheBox, KmemCache};
+use crate::memory::stack::{alloc_stack, KERNEL_STACK_SIZE};
+use crate::sync::SpinLock;
+use crate::time;
+
+pub mod preempt;
+pub mod switch;
+pub mod thread;
+pub mod workqueue;
+
+pub use thread::{Priority, Thread, ThreadId, ThreadState};
+
+/// timer ticks a thread gets to run before someone else at the same priority gets a turn
+pub const TIME_SLICE_TICKS: u64 = 10;
+
+type ThreadMain = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SchedStats {
+    pub context_switches: u64,
+    /// switches forced by the timer rather than the thread giving up the cpu
+    pub preemptions: u64,
+    pub idle_ticks: u64,
+    pub busy_ticks: u64,
+}
+
+struct Scheduler {
+    // boxed so the saved rsp slots don't move while a switch is writing to them
+    threads: BTreeMap<ThreadId, CacheBox<Thread>>,
+    // one round robin queue per priority, shared by every cpu. what each cpu is running lives in
+    // its per-cpu block, along with its idle thread, which never sits in the run queue itself
+    ready: [VecDeque<ThreadId>; Priority::COUNT],
+    // exited threads, freed by whoever runs next since we can't free the stack we're on
+    dead: Vec<CacheBox<Thread>>,
+    stats: SchedStats,
+}
+
+impl Scheduler {
+    fn enqueue(&mut self, id: ThreadId) {
+        let thread = self.threads.get_mut(&id).unwrap();
+        thread.state = ThreadState::Ready;
+        let priority = thread.priority;
+        self.ready[priority as usize].push_back(id);
+        // an idle cpu, or else one running something less important, should take it at the next chance
+        let cpus = || percpu::cpus().filter(|cpu| joined(cpu));
+        let target = cpus().find(|cpu| current_on(cpu) == idle_on(cpu))
+            .or_else(|| cpus().find(|cpu| {
+                self.threads.get(&current_on(cpu)).map_or(true, |current| priority > current.priority)
+            }));
+        if let Some(cpu) = target {
+            cpu.need_resched.store(true, Ordering::SeqCst);
+            // otherwise it wouldn't notice before its next tick
+            if cpu.index != percpu::this_cpu().index {
+                ipi::send_reschedule(cpu.index);
+            }
+        }
+    }
+
+    fn pick_next(&mut self) -> ThreadId {
+        let idle = idle_on(percpu::this_cpu());
+        self.ready.iter_mut().rev()
+            .find_map(|queue| queue.pop_front())
+            .unwrap_or(idle)
+    }
+
+    fn highest_ready(&self) -> Option<Priority> {
+        self.ready.iter().enumerate().rev()
+            .find(|(_, queue)| !queue.is_empty())
+            .map(|(i, _)| self.threads[&self.ready[i][0]].priority)
+    }
+
+    fn ready_count(&self) -> usize {
+        self.ready.iter().map(|queue| queue.len()).sum()
+    }
+
+    /// sets `id`'s state back to ready if it was in `from`
+    fn wake_from(&mut self, id: ThreadId, from: ThreadState) -> bool {
+        match self.threads.get(&id) {
+            Some(thread) if thread.state == from => {
+                self.enqueue(id);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+lazy_static! {
+    // a switch keeps it held until it's on the new stack, so no other cpu can pick up a thread
+    // before its rsp is saved
+    static ref SCHEDULER: SpinLock<Option<Scheduler>> = SpinLock::named("SCHEDULER", lock_class!(), None);
+}
+
+static THREAD_CACHE: KmemCache<Thread> = KmemCache::new("thread", lock_class!());
+
+fn joined(cpu: &PerCpu) -> bool {
+    cpu.idle_thread.load(Ordering::SeqCst) != NO_THREAD
+}
+
+fn current_on(cpu: &PerCpu) -> ThreadId {
+    ThreadId(cpu.current_thread.load(Ordering::SeqCst))
+}
+
+fn idle_on(cpu: &PerCpu) -> ThreadId {
+    ThreadId(cpu.idle_thread.load(Ordering::SeqCst))
+}
+
+// points this cpu at its idle thread and the thread it's running now
+fn join_cpu(current: ThreadId, idle: ThreadId) {
+    let cpu = percpu::this_cpu();
+    cpu.current_thread.store(current.0, Ordering::SeqCst);
+    cpu.idle_thread.store(idle.0, Ordering::SeqCst);
+}
+
+fn alloc_thread(thread: Thread) -> CacheBox<Thread> {
+    THREAD_CACHE.alloc(thread).expect("out of memory for threads")
+}
+
+fn new_thread(name: &'static str, priority: Priority, main: ThreadMain) -> CacheBox<Thread> {
+    let stack = alloc_stack(KERNEL_STACK_SIZE).expect("failed to allocate thread stack");
+    // double boxed so it fits through a single register
+    let arg = Box::into_raw(Box::new(main)) as u64;
+    let rsp = unsafe { switch::init_stack(stack.top().as_u64(), arg) };
+    let mut thread = alloc_thread(Thread::new(name, Some(stack), rsp));
+    thread.priority = priority;
+    thread
+}
+
+/// turns whatever is running right now into the first thread and creates the boot cpu's idle thread
+pub fn init() {
+    let mut boot = alloc_thread(Thread::new("kernel_main", None, 0));
+    boot.state = ThreadState::Running;
+    boot.slice = TIME_SLICE_TICKS;
+    let idle = new_thread("idle", Priority::Low, Box::new(|| idle()));
+    let (boot_id, idle_id) = (boot.id, idle.id);
+
+    let mut threads = BTreeMap::new();
+    threads.insert(boot_id, boot);
+    threads.insert(idle_id, idle);
+    interrupts::without_interrupts(|| {
+        join_cpu(boot_id, idle_id);
+        SCHEDULER.lock().replace(Scheduler {
+            threads,
+            ready: Default::default(),
+            dead: Vec::new(),
+            stats: SchedStats::default(),
+        });
+    });
+}
+
+/// joins an application processor to the scheduler, turning whatever it's running on into its
+/// idle thread. call `idle` afterwards
+pub fn init_ap() {
+    let mut idle = alloc_thread(Thread::new("idle", None, 0));
+    idle.state = ThreadState::Running;
+    idle.priority = Priority::Low;
+    let idle_id = idle.id;
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("task::init hasn't run");
+        scheduler.threads.insert(idle_id, idle);
+        join_cpu(idle_id, idle_id);
+    });
+}
+
+/// whether `init` has run, i.e. whether blocking is possible yet
+pub fn is_running() -> bool {
+    interrupts::without_interrupts(|| SCHEDULER.lock().is_some())
+}
+
+/// what a cpu does when there's nothing else to run
+pub fn idle() -> ! {
+    loop {
+        yield_now();
+        // sleep until an interrupt might have made something runnable
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// the first thing every new thread runs, with `main` being the double boxed closure from `spawn`
+extern "C" fn thread_entry(main: *mut ThreadMain) -> ! {
+    // we got here through `switch_context`, with the scheduler lock still held
+    finish_switch();
+    interrupts::enable();
+    let main = unsafe { Box::from_raw(main) };
+    (*main)();
+    exit();
+}
+
+/// starts `f` on a thread of its own
+pub fn spawn<F: FnOnce() + Send + 'static>(name: &'static str, f: F) -> ThreadId {
+    spawn_with_priority(name, Priority::Normal, f)
+}
+
+pub fn spawn_with_priority<F: FnOnce() + Send + 'static>(name: &'static str, priority: Priority, f: F) -> ThreadId {
+    let thread = new_thread(name, priority, Box::new(f));
+    let id = thread.id;
+    debug!("task: spawned {} ({:?}, {:?})", name, id, priority);
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("task::init hasn't run");
+        scheduler.threads.insert(id, thread);
+        scheduler.enqueue(id);
+    });
+    id
+}
+
+pub fn current_id() -> ThreadId {
+    let id = interrupts::without_interrupts(|| current_on(percpu::this_cpu()));
+    assert_ne!(id.0, NO_THREAD, "task::init hasn't run");
+    id
+}
+
+/// changes a thread's priority, takes effect the next time it's queued
+pub fn set_priority(id: ThreadId, priority: Priority) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("task::init hasn't run");
+        if let Some(thread) = scheduler.threads.get_mut(&id) {
+            let old = thread.priority;
+            thread.priority = priority;
+            if thread.state == ThreadState::Ready {
+                scheduler.ready[old as usize].retain(|queued| *queued != id);
+                scheduler.enqueue(id);
+            }
+        }
+    });
+}
+
+/// gives the cpu to the next ready thread, if there is one
+pub fn yield_now() {
+    switch_away(ThreadState::Ready);
+}
+
+/// blocks the current thread until someone calls `wake` on it. a `wake` that lands after the
+/// waker could find us but before we got here makes this return straight away, so it can
+/// return without the thing we're waiting for having happened: callers recheck and block again
+pub fn block() {
+    switch_away(ThreadState::Blocked);
+}
+
+/// makes a blocked thread runnable again. returns false if it wasn't blocked, in which case its
+/// next `block` won't
+pub fn wake(id: ThreadId) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("task::init hasn't run");
+        if scheduler.wake_from(id, ThreadState::Blocked) {
+            return true;
+        }
+        // it may be on its way to blocking on another cpu
+        if let Some(thread) = scheduler.threads.get_mut(&id) {
+            if thread.state != ThreadState::Exited {
+                thread.wake_pending = true;
+            }
+        }
+        false
+    })
+}
+
+/// puts the current thread to sleep for at least `duration`
+pub fn sleep(duration: Duration) {
+    interrupts::without_interrupts(|| {
+        let id = current_id();
+        // timers run on the boot cpu, so on any other this can fire before we're asleep
+        time::timer::add_timer_in(duration, move || {
+            interrupts::without_interrupts(|| {
+                if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+                    if !scheduler.wake_from(id, ThreadState::Sleeping) {
+                        if let Some(thread) = scheduler.threads.get_mut(&id) {
+                            thread.sleep_over = true;
+                        }
+                    }
+                }
+            });
+        });
+        switch_away(ThreadState::Sleeping);
+    });
+}
+
+/// ends the current thread, waking anyone waiting in `join`
+pub fn exit() -> ! {
+    switch_away(ThreadState::Exited);
+    unreachable!("exited thread got scheduled again");
+}
+
+/// waits for a thread to exit. returns straight away if it already has
+pub fn join(id: ThreadId) {
+    loop {
+        let done = interrupts::without_interrupts(|| {
+            {
+                let mut scheduler = SCHEDULER.lock();
+                let scheduler = scheduler.as_mut().expect("task::init hasn't run");
+                let current = current_on(percpu::this_cpu());
+                assert_ne!(id, current, "thread tried to join itself");
+                match scheduler.threads.get_mut(&id) {
+                    Some(thread) if thread.state != ThreadState::Exited => thread.joiners.push(current),
+                    _ => return true,
+                }
+            }
+            block();
+            false
+        });
+        if done {
+            return;
+        }
+    }
+}
+
+/// called from the timer interrupt after the eoi. charges the tick to whoever is running
+/// and switches threads if their slice ran out or something more important woke up
+pub fn timer_tick() {
+    let slice_over = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = match scheduler.as_mut() {
+            Some(scheduler) => scheduler,
+            None => return false,
+        };
+        let cpu = percpu::this_cpu();
+        if !joined(cpu) {
+            // an ap that's still on its way up
+            return false;
+        }
+        let (idle, current) = (idle_on(cpu), current_on(cpu));
+        let highest_ready = scheduler.highest_ready();
+        if current == idle {
+            scheduler.stats.idle_ticks += 1;
+        } else {
+            scheduler.stats.busy_ticks += 1;
+        }
+        let thread = scheduler.threads.get_mut(&current).unwrap();
+        thread.run_ticks += 1;
+        thread.slice = thread.slice.saturating_sub(1);
+        // only worth switching if there's someone at least as important waiting
+        match highest_ready {
+            Some(priority) => current == idle || priority > thread.priority
+                || (thread.slice == 0 && priority == thread.priority),
+            None => false,
+        }
+    });
+    if slice_over {
+        interrupts::without_interrupts(|| percpu::this_cpu().need_resched.store(true, Ordering::SeqCst));
+    }
+    // the softirq tail runs with interrupts on, a nested tick mustn't switch away from under it
+    if !softirq::in_softirq() {
+        preempt::preempt_point();
+    }
+}
+
+/// called from the reschedule ipi after the eoi, another cpu queued something for us
+pub fn reschedule_interrupt() {
+    if !softirq::in_softirq() {
+        preempt::preempt_point();
+    }
+}
+
+/// switches away if a reschedule is pending. used by the preemption points
+fn reschedule() {
+    let need_resched = interrupts::without_interrupts(|| percpu::this_cpu().need_resched.swap(false, Ordering::SeqCst));
+    if need_resched {
+        interrupts::without_interrupts(|| {
+            if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+                scheduler.stats.preemptions += 1;
+            }
+        });
+        yield_now();
+    }
+}
+
+/// puts the current thread into `state` and switches to the next one
+fn switch_away(state: ThreadState) {
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let (old_rsp, new_rsp) = {
+            let scheduler = match guard.as_mut() {
+                Some(scheduler) => scheduler,
+                // nothing to switch to before init
+                None => return,
+            };
+            let cpu = percpu::this_cpu();
+            let (current, idle) = (current_on(cpu), idle_on(cpu));
+            let old_rsp = {
+                let thread = scheduler.threads.get_mut(&current).unwrap();
+                let woken = match state {
+                    ThreadState::Blocked => mem::take(&mut thread.wake_pending),
+                    ThreadState::Sleeping => mem::take(&mut thread.sleep_over),
+                    _ => false,
+                };
+                if woken {
+                    // already woken
+                    return;
+                }
+                thread.state = state;
+                thread.irq_depth = cpu.irq_depth.load(Ordering::SeqCst);
+                &mut thread.rsp as *mut u64
+            };
+            match state {
+                ThreadState::Ready if current != idle => {
+                    let thread = &scheduler.threads[&current];
+                    // a thread giving up its slice early goes to the back of the line anyway
+                    scheduler.ready[thread.priority as usize].push_back(current);
+                }
+                ThreadState::Exited => {
+                    let thread = scheduler.threads.remove(&current).unwrap();
+                    for joiner in thread.joiners.iter() {
+                        scheduler.wake_from(*joiner, ThreadState::Blocked);
+                    }
+                    // the box keeps `old_rsp` valid until the next thread frees it
+                    scheduler.dead.push(thread);
+                }
+                _ => {}
+            }
+
+            let next = scheduler.pick_next();
+            let next_thread = scheduler.threads.get_mut(&next).unwrap();
+            next_thread.state = ThreadState::Running;
+            next_thread.slice = TIME_SLICE_TICKS;
+            if next == current {
+                // nothing else wants to run
+                return;
+            }
+            next_thread.switches += 1;
+            let new_rsp = next_thread.rsp;
+            cpu.irq_depth.store(next_thread.irq_depth, Ordering::SeqCst);
+            cpu.current_thread.store(next.0, Ordering::SeqCst);
+            scheduler.stats.context_switches += 1;
+            (old_rsp, new_rsp)
+        };
+        // the lock stays held through the switch: the thread we're leaving may already be in the
+        // run queue, and another cpu mustn't pick it up until `switch_context` has saved its rsp.
+        // whoever we switch to releases it in `finish_switch`
+        mem::forget(guard);
+        unsafe {
+            switch::switch_context(old_rsp, new_rsp);
+        }
+        finish_switch();
+    });
+}
+
+/// cleanup that has to happen on the far side of a switch. drops the scheduler lock the
+/// switching thread left held
+fn finish_switch() {
+    let dead = {
+        unsafe {
+            SCHEDULER.unlock_forgotten();
+        }
+        let mut scheduler = SCHEDULER.lock();
+        mem::take(&mut scheduler.as_mut().unwrap().dead)
+    };
+    // dropping frees the stacks, outside the lock since that goes through vmalloc
+    drop(dead);
+}
+
+pub fn stats() -> SchedStats {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().as_ref().map(|scheduler| scheduler.stats).unwrap_or_default()
+    })
+}
+
+pub fn print_stats() {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        let scheduler = match scheduler.as_ref() {
+            Some(scheduler) => scheduler,
+            None => return,
+        };
+        let stats = scheduler.stats;
+        println!("sched: {} switches ({} preemptions), {} ready, {} busy / {} idle ticks",
+            stats.context_switches, stats.preemptions, scheduler.ready_count(), stats.busy_ticks, stats.idle_ticks);
+        for thread in scheduler.threads.values() {
+            println!("  {:>3} {:<16} {:?} {:?}, {} ticks, {} switches",
+                thread.id.0, thread.name, thread.state, thread.priority, thread.run_ticks, thread.switches);
+        }
+    });
+}
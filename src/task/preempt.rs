@@ -0,0 +1,53 @@
+use core::sync::atomic::Ordering;
+use x86_64::instructions::interrupts;
+use crate::internals::percpu;
+
+// the count lives in each cpu's per-cpu block, the timer won't switch threads on a cpu while
+// its count isn't 0
+
+/// keeps the current thread on the cpu until the matching `preempt_enable`. nests.
+/// interrupts still happen, they just won't switch threads
+pub fn preempt_disable() {
+    // we could be moved to another cpu between finding ours and counting, unless interrupts are off
+    interrupts::without_interrupts(|| {
+        percpu::this_cpu().preempt_count.fetch_add(1, Ordering::SeqCst);
+    });
+}
+
+/// undoes one `preempt_disable`, switching threads straight away if the timer wanted to meanwhile
+pub fn preempt_enable() {
+    // preemption is off, so we're still on the cpu that counted it
+    let previous = percpu::this_cpu().preempt_count.fetch_sub(1, Ordering::SeqCst);
+    assert_ne!(previous, 0, "preempt_enable without preempt_disable");
+    // with interrupts off the caller is in the middle of something, leave it for the next tick
+    if previous == 1 && interrupts::are_enabled() {
+        preempt_point();
+    }
+}
+
+pub fn preemptible() -> bool {
+    interrupts::without_interrupts(|| percpu::this_cpu().preempt_count.load(Ordering::SeqCst) == 0)
+}
+
+/// switches threads if one is due and preemption is allowed
+pub(super) fn preempt_point() {
+    if preemptible() {
+        super::reschedule();
+    }
+}
+
+/// disables preemption for as long as it's alive
+pub struct PreemptGuard(());
+
+impl PreemptGuard {
+    pub fn new() -> PreemptGuard {
+        preempt_disable();
+        PreemptGuard(())
+    }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        preempt_enable();
+    }
+}
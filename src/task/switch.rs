@@ -0,0 +1,60 @@
+use core::arch::asm;
+
+/// saves the callee-saved registers on the current stack, stores the stack pointer in `*old_rsp`,
+/// then loads `new_rsp` and pops the next thread's registers. returns on the other thread,
+/// and returns here once something switches back to us
+#[naked]
+pub unsafe extern "C" fn switch_context(old_rsp: *mut u64, new_rsp: u64) {
+    asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+        options(noreturn),
+    );
+}
+
+/// where new threads "return" to from their first switch. the entry argument was left in r12
+#[naked]
+pub unsafe extern "C" fn thread_trampoline() -> ! {
+    asm!(
+        "mov rdi, r12",
+        "call {entry}",
+        "ud2",
+        entry = sym super::thread_entry,
+        options(noreturn),
+    );
+}
+
+/// builds the stack a new thread starts from, so that the first `switch_context` into it
+/// pops zeroed registers (r12 = `arg`) and returns into `thread_trampoline`.
+/// returns the stack pointer to hand to `switch_context`
+pub unsafe fn init_stack(top: u64, arg: u64) -> u64 {
+    let top = top & !0xf;
+    let frame = [
+        0,     // r15
+        0,     // r14
+        0,     // r13
+        arg,   // r12
+        0,     // rbx
+        0,     // rbp
+        // after this is popped rsp is back at `top`, aligned for the trampoline's call
+        thread_trampoline as u64,
+    ];
+    let rsp = top - (frame.len() as u64 * 8);
+    for (i, word) in frame.iter().enumerate() {
+        *((rsp + i as u64 * 8) as *mut u64) = *word;
+    }
+    rsp
+}
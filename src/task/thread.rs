@@ -0,0 +1,83 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::memory::stack::KernelStack;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(pub u64);
+
+impl ThreadId {
+    pub(super) fn next() -> ThreadId {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT.fetch_add(1, Ordering::SeqCst))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThreadState {
+    /// waiting in the run queue
+    Ready,
+    Running,
+    /// waiting for something to `wake` it
+    Blocked,
+    /// waiting for its sleep timer to go off
+    Sleeping,
+    /// finished, waiting to have its stack freed
+    Exited,
+}
+
+/// threads at a higher priority always run first, threads at the same priority take turns
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low = 0,
+    Normal = 1,
+    High = 2,
+}
+
+impl Priority {
+    pub const COUNT: usize = 3;
+}
+
+pub struct Thread {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: ThreadState,
+    pub priority: Priority,
+    // ticks left before the thread gets preempted
+    pub(super) slice: u64,
+    /// timer ticks spent running
+    pub run_ticks: u64,
+    /// times the thread has been switched to
+    pub switches: u64,
+    // saved stack pointer while the thread isn't running, see `switch::switch_context`
+    pub(super) rsp: u64,
+    // none for the boot thread, which keeps running on the stack the bootloader gave us
+    pub(super) stack: Option<KernelStack>,
+    // threads blocked in `join` on this one
+    pub(super) joiners: Vec<ThreadId>,
+    // a `wake` arrived before the thread got around to blocking, so its next `block` returns straight away
+    pub(super) wake_pending: bool,
+    // the same for `sleep`: its timer fired on another cpu before the thread was asleep
+    pub(super) sleep_over: bool,
+    // `PerCpu::irq_depth` while switched out, nonzero for threads preempted from the timer interrupt
+    pub(super) irq_depth: usize,
+}
+
+impl Thread {
+    pub(super) fn new(name: &'static str, stack: Option<KernelStack>, rsp: u64) -> Thread {
+        Thread {
+            id: ThreadId::next(),
+            name,
+            state: ThreadState::Ready,
+            priority: Priority::Normal,
+            slice: 0,
+            run_ticks: 0,
+            switches: 0,
+            rsp,
+            stack,
+            joiners: Vec::new(),
+            wake_pending: false,
+            sleep_over: false,
+            irq_depth: 0,
+        }
+    }
+}
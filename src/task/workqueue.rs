@@ -0,0 +1,84 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::{debug, lock_class};
+use crate::sync::{LockClassKey, SpinLock, WaitQueue};
+
+pub type Work = Box<dyn FnOnce() + Send>;
+
+/// work for kernel worker threads to run later, in thread context with interrupts on, so it can take
+/// sleeping locks and print. interrupt handlers use it to push the slow part of their job out of
+/// interrupt context. work queued before the workers start waits for them
+pub struct WorkQueue {
+    name: &'static str,
+    queue: SpinLock<VecDeque<Work>>,
+    // workers sleep here while the queue is empty
+    work_available: WaitQueue,
+    // queued or running, `flush` waits on `idle` for it to hit 0
+    pending: AtomicUsize,
+    idle: WaitQueue,
+}
+
+/// the queue `queue_work` uses, with one worker started by `init`
+pub static SYSTEM_WQ: WorkQueue = WorkQueue::new("events", lock_class!());
+
+impl WorkQueue {
+    /// `class` should come from `lock_class!()` at the definition, so each queue is its own lockdep class
+    pub const fn new(name: &'static str, class: &'static LockClassKey) -> WorkQueue {
+        WorkQueue {
+            name,
+            queue: SpinLock::named(name, class, VecDeque::new()),
+            work_available: WaitQueue::new(),
+            pending: AtomicUsize::new(0),
+            idle: WaitQueue::new(),
+        }
+    }
+
+    /// spawns `workers` threads to run this queue's work. needs `task::init`
+    pub fn start(&'static self, workers: usize) {
+        for _ in 0..workers {
+            super::spawn(self.name, move || self.worker());
+        }
+        debug!("workqueue: {} started with {} worker(s)", self.name, workers);
+    }
+
+    /// queues `f` to run on one of the workers. fine from interrupt handlers
+    pub fn queue<F: FnOnce() + Send + 'static>(&self, f: F) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        self.queue.lock().push_back(Box::new(f));
+        self.work_available.wake_one();
+    }
+
+    /// sleeps until everything queued so far (and anything queued meanwhile) has run.
+    /// not from a worker of the same queue, it would wait on itself
+    #[track_caller]
+    pub fn flush(&self) {
+        self.idle.wait_until(|| self.pending.load(Ordering::SeqCst) == 0);
+    }
+
+    fn worker(&'static self) {
+        loop {
+            let mut work = None;
+            self.work_available.wait_until(|| {
+                work = self.queue.lock().pop_front();
+                work.is_some()
+            });
+            if let Some(work) = work {
+                work();
+            }
+            if self.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+                self.idle.wake_all();
+            }
+        }
+    }
+}
+
+/// queues `f` on the system work queue
+pub fn queue_work<F: FnOnce() + Send + 'static>(f: F) {
+    SYSTEM_WQ.queue(f);
+}
+
+/// starts the system work queue's worker
+pub fn init() {
+    SYSTEM_WQ.start(1);
+}
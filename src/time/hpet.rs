@@ -0,0 +1,231 @@
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use acpi::HpetInfo;
+use x2apic::ioapic::IrqFlags;
+use x86_64::PhysAddr;
+use crate::debug;
+use crate::internals::interrupts::{self, Irq, IrqError, IrqHandler, IrqSource};
+use crate::memory::mmio::ioremap;
+
+const FS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+// register offsets
+const CAPABILITIES: u64 = 0x000;
+const CONFIG: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0f0;
+const fn timer_config(n: u8) -> u64 { 0x100 + 0x20 * n as u64 }
+const fn timer_comparator(n: u8) -> u64 { 0x108 + 0x20 * n as u64 }
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+const CAP_COUNTER_64: u64 = 1 << 13;
+
+const TIMER_LEVEL: u64 = 1 << 1;
+const TIMER_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+// read only, the comparator can do 64 bits
+const TIMER_64BIT_CAP: u64 = 1 << 5;
+const TIMER_32BIT: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1f << TIMER_ROUTE_SHIFT;
+const TIMER_FSB: u64 = 1 << 14;
+
+// virtual address of the register block, 0 until init
+static BASE: AtomicU64 = AtomicU64::new(0);
+// length of one counter tick in femtoseconds
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+static COUNTER_64: AtomicBool = AtomicBool::new(false);
+static TIMER_COUNT: AtomicU32 = AtomicU32::new(0);
+// comparators currently handed out, one bit each
+static TIMERS_USED: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug)]
+pub enum HpetError {
+    NotPresent,
+    NoFreeComparator,
+    NoUsableGsi,
+    Irq(IrqError),
+}
+
+/// a comparator armed with `arm_oneshot`, give it back with `free_comparator`
+#[derive(Clone, Copy, Debug)]
+pub struct Comparator {
+    pub timer: u8,
+    pub irq: Irq,
+}
+
+unsafe fn read(offset: u64) -> u64 {
+    ptr::read_volatile((BASE.load(Ordering::Relaxed) + offset) as *const u64)
+}
+
+unsafe fn write(offset: u64, value: u64) {
+    ptr::write_volatile((BASE.load(Ordering::Relaxed) + offset) as *mut u64, value)
+}
+
+/// maps the hpet described by the acpi table and starts its main counter
+pub fn init(info: &HpetInfo) {
+    let base = ioremap(PhysAddr::new(info.base_address as u64), 4096)
+        .unwrap_or_else(|e| panic!("failed to map hpet: {:?}", e))
+        .leak();
+    BASE.store(base.as_u64(), Ordering::SeqCst);
+
+    unsafe {
+        let caps = read(CAPABILITIES);
+        let period_fs = caps >> 32;
+        let timers = ((caps >> 8) & 0x1f) as u32 + 1;
+        PERIOD_FS.store(period_fs, Ordering::SeqCst);
+        COUNTER_64.store(caps & CAP_COUNTER_64 != 0, Ordering::SeqCst);
+        TIMER_COUNT.store(timers, Ordering::SeqCst);
+
+        // stop everything, go through the ioapic instead of the legacy pit/rtc routing
+        write(CONFIG, read(CONFIG) & !(CONFIG_ENABLE | CONFIG_LEGACY_ROUTE));
+        for n in 0..timers as u8 {
+            write(timer_config(n), read(timer_config(n)) & !(TIMER_ENABLE | TIMER_PERIODIC | TIMER_FSB));
+        }
+        write(MAIN_COUNTER, 0);
+        write(CONFIG, read(CONFIG) | CONFIG_ENABLE);
+
+        debug!("hpet: {} Hz, {} comparators, {} bit counter", frequency(), timers,
+            if is_64bit() { 64 } else { 32 });
+    }
+}
+
+pub fn is_present() -> bool {
+    BASE.load(Ordering::SeqCst) != 0
+}
+
+/// a 32 bit counter wraps every few minutes, so it's only good for measuring short intervals
+pub fn is_64bit() -> bool {
+    COUNTER_64.load(Ordering::SeqCst)
+}
+
+pub fn frequency() -> u64 {
+    match PERIOD_FS.load(Ordering::SeqCst) {
+        0 => 0,
+        period => FS_PER_SEC / period,
+    }
+}
+
+/// raw main counter value
+pub fn counter() -> u64 {
+    if is_64bit() {
+        unsafe { read(MAIN_COUNTER) }
+    } else {
+        unsafe { read(MAIN_COUNTER) & 0xffff_ffff }
+    }
+}
+
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    (ticks as u128 * PERIOD_FS.load(Ordering::SeqCst) as u128 / 1_000_000) as u64
+}
+
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    (ns as u128 * 1_000_000 / PERIOD_FS.load(Ordering::SeqCst) as u128) as u64
+}
+
+/// nanoseconds since the main counter was started
+pub fn counter_ns() -> u64 {
+    ticks_to_ns(counter())
+}
+
+// difference between two counter reads, taking a 32 bit wrap into account
+fn elapsed(start: u64, now: u64) -> u64 {
+    if is_64bit() {
+        now.wrapping_sub(start)
+    } else {
+        now.wrapping_sub(start) & 0xffff_ffff
+    }
+}
+
+/// busy waits on the main counter, the calibration reference when there's an hpet
+pub fn wait_us(us: u64) {
+    let ticks = ns_to_ticks(us * 1000);
+    let start = counter();
+    while elapsed(start, counter()) < ticks {
+        core::hint::spin_loop();
+    }
+}
+
+fn claim_timer() -> Option<u8> {
+    let count = TIMER_COUNT.load(Ordering::SeqCst);
+    for n in 0..count {
+        let bit = 1 << n;
+        if TIMERS_USED.fetch_or(bit, Ordering::SeqCst) & bit == 0 {
+            return Some(n as u8);
+        }
+    }
+    None
+}
+
+fn release_timer(timer: u8) {
+    TIMERS_USED.fetch_and(!(1 << timer), Ordering::SeqCst);
+}
+
+/// fires `handler` once, `ns` nanoseconds from now, on a comparator routed through the ioapic
+pub fn arm_oneshot(ns: u64, handler: IrqHandler) -> Result<Comparator, HpetError> {
+    if !is_present() {
+        return Err(HpetError::NotPresent);
+    }
+    let timer = claim_timer().ok_or(HpetError::NoFreeComparator)?;
+    let irq = match route_timer(timer, handler) {
+        Ok(irq) => irq,
+        Err(e) => {
+            release_timer(timer);
+            return Err(e);
+        }
+    };
+
+    unsafe {
+        let mut config = read(timer_config(timer));
+        config &= !(TIMER_ROUTE_MASK | TIMER_PERIODIC | TIMER_LEVEL | TIMER_FSB);
+        config |= (irq.gsi as u64) << TIMER_ROUTE_SHIFT;
+        // a 64 bit counter can still have 32 bit comparators, those only match its low half
+        let wide = is_64bit() && config & TIMER_64BIT_CAP != 0;
+        if !wide {
+            config |= TIMER_32BIT;
+        }
+        write(timer_config(timer), config);
+
+        // the comparator only fires on an exact match, so if the counter already ran past it
+        // while we were writing, try again further out
+        let max_ticks = if wide { u64::MAX } else { u32::MAX as u64 };
+        let mut ticks = ns_to_ticks(ns).clamp(1, max_ticks);
+        loop {
+            let start = counter();
+            write(timer_comparator(timer), start.wrapping_add(ticks));
+            write(timer_config(timer), config | TIMER_ENABLE);
+            if elapsed(start, counter()) < ticks {
+                break;
+            }
+            ticks = ticks.saturating_mul(2).min(max_ticks);
+        }
+    }
+
+    Ok(Comparator { timer, irq })
+}
+
+// finds a gsi the comparator can be wired to that nobody else is using
+fn route_timer(timer: u8, handler: IrqHandler) -> Result<Irq, HpetError> {
+    let routes = unsafe { read(timer_config(timer)) } >> 32;
+    let mut last_error = HpetError::NoUsableGsi;
+    for gsi in 0..32 {
+        if routes & (1 << gsi) == 0 {
+            continue;
+        }
+        match interrupts::request_irq_with_flags(IrqSource::Gsi(gsi), IrqFlags::empty(), handler) {
+            Ok(irq) => return Ok(irq),
+            Err(e) => last_error = HpetError::Irq(e),
+        }
+    }
+    Err(last_error)
+}
+
+/// disarms a comparator and frees its irq
+pub fn free_comparator(comparator: Comparator) {
+    unsafe {
+        let config = read(timer_config(comparator.timer));
+        write(timer_config(comparator.timer), config & !TIMER_ENABLE);
+    }
+    interrupts::free_irq(comparator.irq);
+    release_timer(comparator.timer);
+}
@@ -0,0 +1,224 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::time::Duration;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use crate::debug;
+use crate::internals::{cpu, percpu};
+use crate::percpu;
+use crate::task;
+
+pub mod hpet;
+pub mod pit;
+pub mod rtc;
+pub mod timer;
+
+pub const NS_PER_SEC: u64 = 1_000_000_000;
+/// how often the periodic tick fires
+pub const TICK_HZ: u64 = 1000;
+// how long to measure against the reference clock when calibrating
+const CALIBRATION_US: u64 = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TickMode {
+    /// fires every 1/TICK_HZ seconds
+    Periodic,
+    /// fires once after whatever `arm_oneshot` was given, then stays quiet
+    OneShot,
+}
+
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+static INVARIANT_TSC: AtomicBool = AtomicBool::new(false);
+// lapic timer ticks per second, after the divider
+static LAPIC_TIMER_HZ: AtomicU64 = AtomicU64::new(0);
+// nanoseconds accounted for by timer interrupts so far, the clock of last resort
+static TICK_NS: AtomicU64 = AtomicU64::new(0);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// hpet time at `init`, so the hpet clock starts from zero like the others
+static HPET_BASE_NS: AtomicU64 = AtomicU64::new(0);
+// unix time in ns when the monotonic clock read zero, 0 until the rtc has been read
+static BOOT_UNIX_NS: AtomicU64 = AtomicU64::new(0);
+
+percpu! {
+    // every cpu programs its own lapic timer
+    static ONESHOT: AtomicBool = AtomicBool::new(false);
+    // nanoseconds covered by the currently programmed timer interrupt
+    static TICK_PERIOD_NS: AtomicU64 = AtomicU64::new(0);
+}
+
+/// invariant tsc runs at a constant rate through frequency and power state changes
+pub fn check_invariant_tsc() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended_leaf >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+fn ns_to_lapic_ticks(ns: u64) -> u32 {
+    let ticks = ns as u128 * LAPIC_TIMER_HZ.load(Ordering::SeqCst) as u128 / NS_PER_SEC as u128;
+    ticks.clamp(1, u32::MAX as u128) as u32
+}
+
+// busy waits on the most accurate clock that doesn't depend on what we're calibrating
+fn reference_wait_us(us: u64) {
+    if hpet::is_present() {
+        hpet::wait_us(us);
+    } else {
+        pit::wait_us(us);
+    }
+}
+
+/// measures the tsc and lapic timer against the hpet, or the pit when there isn't one
+fn calibrate() -> (u64, u64) {
+    cpu::lapic_timer_oneshot(u32::MAX);
+    let tsc_start = rdtsc();
+    reference_wait_us(CALIBRATION_US);
+    let tsc_end = rdtsc();
+    let lapic_elapsed = u32::MAX - cpu::lapic_timer_current();
+    cpu::lapic_timer_stop();
+
+    let tsc_hz = (tsc_end - tsc_start) * 1_000_000 / CALIBRATION_US;
+    let lapic_hz = lapic_elapsed as u64 * 1_000_000 / CALIBRATION_US;
+    (tsc_hz, lapic_hz)
+}
+
+/// calibrates the clocks and starts the periodic tick. the local apic has to be enabled already,
+/// and the hpet set up if there is one
+pub fn init() {
+    interrupts::without_interrupts(|| {
+        let (tsc_hz, lapic_hz) = calibrate();
+        debug!("time: tsc {} Hz, lapic timer {} Hz", tsc_hz, lapic_hz);
+        TSC_HZ.store(tsc_hz, Ordering::SeqCst);
+        TSC_BASE.store(rdtsc(), Ordering::SeqCst);
+        if hpet::is_present() {
+            HPET_BASE_NS.store(hpet::counter_ns(), Ordering::SeqCst);
+        }
+        INVARIANT_TSC.store(check_invariant_tsc(), Ordering::SeqCst);
+        LAPIC_TIMER_HZ.store(lapic_hz, Ordering::SeqCst);
+    });
+    start_periodic_tick();
+}
+
+/// starts the periodic tick on this cpu's lapic timer
+pub fn start_periodic_tick() {
+    let period_ns = NS_PER_SEC / TICK_HZ;
+    interrupts::without_interrupts(|| {
+        ONESHOT.get().store(false, Ordering::SeqCst);
+        TICK_PERIOD_NS.get().store(period_ns, Ordering::SeqCst);
+        cpu::lapic_timer_periodic(ns_to_lapic_ticks(period_ns));
+    });
+}
+
+/// stops this cpu's periodic tick and fires a single timer interrupt `ns` from now
+pub fn arm_oneshot(ns: u64) {
+    interrupts::without_interrupts(|| {
+        ONESHOT.get().store(true, Ordering::SeqCst);
+        TICK_PERIOD_NS.get().store(ns, Ordering::SeqCst);
+        cpu::lapic_timer_oneshot(ns_to_lapic_ticks(ns));
+    });
+}
+
+/// how this cpu's lapic timer is programmed
+pub fn tick_mode() -> TickMode {
+    if interrupts::without_interrupts(|| ONESHOT.get().load(Ordering::SeqCst)) {
+        TickMode::OneShot
+    } else {
+        TickMode::Periodic
+    }
+}
+
+/// called from the lapic timer interrupt on every cpu, only the boot cpu keeps time
+pub fn tick() {
+    if !percpu::this_cpu().is_bsp {
+        return;
+    }
+    TICKS.fetch_add(1, Ordering::SeqCst);
+    TICK_NS.fetch_add(TICK_PERIOD_NS.get().load(Ordering::SeqCst), Ordering::SeqCst);
+    timer::check_expired();
+}
+
+/// timer interrupts taken since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+pub fn tsc_frequency() -> u64 {
+    TSC_HZ.load(Ordering::SeqCst)
+}
+
+pub fn lapic_timer_frequency() -> u64 {
+    LAPIC_TIMER_HZ.load(Ordering::SeqCst)
+}
+
+pub fn has_invariant_tsc() -> bool {
+    INVARIANT_TSC.load(Ordering::SeqCst)
+}
+
+fn tsc_to_ns(tsc_hz: u64) -> u64 {
+    let elapsed = rdtsc().wrapping_sub(TSC_BASE.load(Ordering::SeqCst));
+    (elapsed as u128 * NS_PER_SEC as u128 / tsc_hz as u128) as u64
+}
+
+/// nanoseconds since `init`. uses the tsc when it's invariant, then a 64 bit hpet, and
+/// otherwise counts timer interrupts, which only gets as fine as the tick
+pub fn monotonic_ns() -> u64 {
+    let tsc_hz = TSC_HZ.load(Ordering::SeqCst);
+    if INVARIANT_TSC.load(Ordering::SeqCst) && tsc_hz != 0 {
+        tsc_to_ns(tsc_hz)
+    } else if hpet::is_present() && hpet::is_64bit() {
+        hpet::counter_ns().saturating_sub(HPET_BASE_NS.load(Ordering::SeqCst))
+    } else {
+        TICK_NS.load(Ordering::SeqCst)
+    }
+}
+
+/// spins for at least `us` microseconds, fine to call with interrupts off
+pub fn busy_wait_us(us: u64) {
+    let tsc_hz = TSC_HZ.load(Ordering::SeqCst);
+    if tsc_hz == 0 {
+        // not calibrated yet
+        pit::wait_us(us);
+        return;
+    }
+    // even a tsc that isn't invariant is good enough for a short spin
+    let deadline = rdtsc() + us * tsc_hz / 1_000_000;
+    while rdtsc() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// waits at least `ms` milliseconds. puts the thread to sleep once the scheduler is running,
+/// before that it halts between interrupts, and with interrupts off it has to spin instead
+pub fn sleep_ms(ms: u64) {
+    if !interrupts::are_enabled() {
+        busy_wait_us(ms * 1000);
+        return;
+    }
+    if task::is_running() {
+        task::sleep(Duration::from_millis(ms));
+        return;
+    }
+    let deadline = monotonic_ns() + ms * 1_000_000;
+    while monotonic_ns() < deadline {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// reads the rtc once and anchors wall clock time to the monotonic clock. has to run after `init`
+pub fn init_wall_clock() {
+    let datetime = rtc::read_datetime();
+    let unix_ns = datetime.to_unix() * NS_PER_SEC;
+    BOOT_UNIX_NS.store(unix_ns.saturating_sub(monotonic_ns()), Ordering::SeqCst);
+}
+
+/// time since the unix epoch, or since boot if the rtc hasn't been read yet
+pub fn now() -> Duration {
+    Duration::from_nanos(BOOT_UNIX_NS.load(Ordering::SeqCst) + monotonic_ns())
+}
+
+/// the current date and time in utc
+pub fn now_datetime() -> rtc::DateTime {
+    rtc::DateTime::from_unix(now().as_secs())
+}
@@ -0,0 +1,38 @@
+use crate::serial::{command, read};
+
+/// the pit counts down at this rate no matter what the rest of the machine is doing
+pub const PIT_HZ: u64 = 1_193_182;
+
+const CHANNEL2_DATA: u16 = 0x42;
+const MODE_COMMAND: u16 = 0x43;
+// bit 0 gates channel 2, bit 1 connects it to the speaker, bit 5 is channel 2's output
+const SPEAKER_CONTROL: u16 = 0x61;
+
+// longest wait a single 16 bit countdown can do, a bit under 55ms
+const MAX_WAIT_US: u64 = 65535 * 1_000_000 / PIT_HZ;
+
+/// busy waits on pit channel 2. doesn't need interrupts, which makes it the reference
+/// everything else gets calibrated against
+pub fn wait_us(mut us: u64) {
+    while us > 0 {
+        let chunk = us.min(MAX_WAIT_US);
+        countdown((chunk * PIT_HZ / 1_000_000).max(1) as u16);
+        us -= chunk;
+    }
+}
+
+fn countdown(count: u16) {
+    // gate on, speaker off
+    let control = read(SPEAKER_CONTROL) & !0x02;
+    command(SPEAKER_CONTROL, control | 0x01);
+    // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+    command(MODE_COMMAND, 0b1011_0000);
+    command(CHANNEL2_DATA, count as u8);
+    command(CHANNEL2_DATA, (count >> 8) as u8);
+    // pulse the gate so the count starts now
+    command(SPEAKER_CONTROL, control & !0x01);
+    command(SPEAKER_CONTROL, control | 0x01);
+    while read(SPEAKER_CONTROL) & 0x20 == 0 {
+        core::hint::spin_loop();
+    }
+}
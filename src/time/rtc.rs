@@ -0,0 +1,200 @@
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::internals::interrupts::{self, Irq, IrqError, IrqHandler, IrqSource};
+use crate::lock_class;
+use crate::serial::{command, read};
+use crate::sync::SpinLock;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_PERIODIC: u8 = 1 << 6;
+const HOUR_PM: u8 = 1 << 7;
+
+// the cmos doesn't reliably tell us the century, so assume this one
+const CENTURY: u16 = 2000;
+const RTC_ISA_IRQ: u8 = 8;
+
+static PERIODIC_HANDLER: AtomicUsize = AtomicUsize::new(0);
+static PERIODIC_IRQ: SpinLock<Option<Irq>> = SpinLock::named("PERIODIC_IRQ", lock_class!(), None);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// seconds since 1970-01-01 00:00:00 utc
+    pub fn to_unix(&self) -> u64 {
+        // days from civil, shifted so the year starts in march and leap days come last
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+        days as u64 * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    pub fn from_unix(secs: u64) -> DateTime {
+        let days = (secs / 86400) as i64 + 719468;
+        let secs_of_day = secs % 86400;
+        let era = days.div_euclid(146097);
+        let day_of_era = days - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+        let year = (year_of_era + era * 400 + if month <= 2 { 1 } else { 0 }) as u16;
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+fn read_register(register: u8) -> u8 {
+    command(CMOS_ADDRESS, register);
+    read(CMOS_DATA)
+}
+
+fn write_register(register: u8, value: u8) {
+    command(CMOS_ADDRESS, register);
+    command(CMOS_DATA, value);
+}
+
+fn update_in_progress() -> bool {
+    read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0f) + (value >> 4) * 10
+}
+
+// raw register values, still in whatever format the rtc is set to
+fn read_raw() -> [u8; 6] {
+    while update_in_progress() {
+        core::hint::spin_loop();
+    }
+    [
+        read_register(REG_SECONDS),
+        read_register(REG_MINUTES),
+        read_register(REG_HOURS),
+        read_register(REG_DAY),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+    ]
+}
+
+/// reads the current date and time from the cmos, in utc
+pub fn read_datetime() -> DateTime {
+    let (raw, status_b) = without_interrupts(|| {
+        // an update can still sneak in between the check and the reads, so read until we get
+        // the same thing twice in a row
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, read_register(REG_STATUS_B))
+    });
+
+    let [mut second, mut minute, mut hour, mut day, mut month, mut year] = raw;
+    let pm = hour & HOUR_PM != 0;
+    hour &= !HOUR_PM;
+    if status_b & STATUS_B_BINARY == 0 {
+        second = from_bcd(second);
+        minute = from_bcd(minute);
+        hour = from_bcd(hour);
+        day = from_bcd(day);
+        month = from_bcd(month);
+        year = from_bcd(year);
+    }
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 hour clock: 12am is midnight, 12pm is noon
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    DateTime {
+        year: CENTURY + year as u16,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
+
+fn rtc_irq(vector: u8) {
+    // the rtc won't raise another interrupt until status c has been read
+    read_register(REG_STATUS_C);
+    let handler = PERIODIC_HANDLER.load(Ordering::SeqCst);
+    if handler != 0 {
+        let handler: IrqHandler = unsafe { core::mem::transmute(handler) };
+        handler(vector);
+    }
+}
+
+/// starts the rtc periodic interrupt at 32768 >> (rate - 1) Hz, rate 3 (8192 Hz) to 15 (2 Hz)
+pub fn enable_periodic(rate: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    let rate = rate.clamp(3, 15);
+    let mut periodic_irq = PERIODIC_IRQ.lock();
+    PERIODIC_HANDLER.store(handler as usize, Ordering::SeqCst);
+    if periodic_irq.is_none() {
+        periodic_irq.replace(interrupts::request_irq(IrqSource::Isa(RTC_ISA_IRQ), rtc_irq)?);
+    }
+    without_interrupts(|| {
+        write_register(REG_STATUS_A, (read_register(REG_STATUS_A) & 0xf0) | rate);
+        write_register(REG_STATUS_B, read_register(REG_STATUS_B) | STATUS_B_PERIODIC);
+        // clear anything that was already pending
+        read_register(REG_STATUS_C);
+    });
+    Ok(())
+}
+
+pub fn disable_periodic() {
+    without_interrupts(|| {
+        write_register(REG_STATUS_B, read_register(REG_STATUS_B) & !STATUS_B_PERIODIC);
+    });
+    if let Some(irq) = PERIODIC_IRQ.lock().take() {
+        interrupts::free_irq(irq);
+    }
+    PERIODIC_HANDLER.store(0, Ordering::SeqCst);
+}
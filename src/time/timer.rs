@@ -0,0 +1,157 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::internals::softirq::{self, SoftIrq};
+use crate::time::monotonic_ns;
+
+pub type TimerCallback = Box<dyn FnMut() + Send>;
+
+/// identifies an armed timer, pass it to `cancel_timer` to get rid of it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+struct Timer {
+    callback: TimerCallback,
+    // re-armed this far after each expiry, if set
+    period: Option<u64>,
+}
+
+struct TimerQueue {
+    // ordered by deadline, the id breaks ties so timers with the same deadline can coexist
+    queue: BTreeMap<(u64, TimerId), Timer>,
+    // id -> deadline, so cancelling doesn't have to search the queue
+    deadlines: BTreeMap<TimerId, u64>,
+    // the periodic timer whose callback is running right now, and whether it got cancelled meanwhile
+    running: Option<TimerId>,
+    running_cancelled: bool,
+}
+
+impl TimerQueue {
+    fn insert(&mut self, id: TimerId, deadline: u64, timer: Timer) {
+        self.queue.insert((deadline, id), timer);
+        self.deadlines.insert(id, deadline);
+        self.update_next_deadline();
+    }
+
+    fn remove(&mut self, id: TimerId) -> Option<Timer> {
+        let deadline = self.deadlines.remove(&id)?;
+        let timer = self.queue.remove(&(deadline, id));
+        self.update_next_deadline();
+        timer
+    }
+
+    /// takes the earliest timer if it's due
+    fn pop_expired(&mut self, now: u64) -> Option<(TimerId, u64, Timer)> {
+        let (deadline, id) = *self.queue.keys().next()?;
+        if deadline > now {
+            return None;
+        }
+        let timer = self.remove(id)?;
+        if timer.period.is_some() {
+            self.running = Some(id);
+            self.running_cancelled = false;
+        }
+        Some((id, deadline, timer))
+    }
+
+    fn update_next_deadline(&self) {
+        let next = self.queue.keys().next().map(|(deadline, _)| *deadline).unwrap_or(u64::MAX);
+        NEXT_DEADLINE.store(next, Ordering::SeqCst);
+    }
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+// earliest deadline in the queue, so the tick can check it without taking the lock
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+lazy_static! {
+    static ref TIMERS: Mutex<TimerQueue> = Mutex::new(TimerQueue {
+        queue: BTreeMap::new(),
+        deadlines: BTreeMap::new(),
+        running: None,
+        running_cancelled: false,
+    });
+}
+
+fn arm(deadline: u64, period: Option<u64>, callback: TimerCallback) -> TimerId {
+    let id = TimerId(NEXT_ID.fetch_add(1, Ordering::SeqCst));
+    interrupts::without_interrupts(|| {
+        TIMERS.lock().insert(id, deadline, Timer { callback, period });
+    });
+    id
+}
+
+/// runs `callback` once the monotonic clock reaches `deadline` (in ns). callbacks run in
+/// softirq context: interrupts are on, but they mustn't sleep
+pub fn add_timer<F: FnMut() + Send + 'static>(deadline: u64, callback: F) -> TimerId {
+    arm(deadline, None, Box::new(callback))
+}
+
+/// runs `callback` once, `delay` from now
+pub fn add_timer_in<F: FnMut() + Send + 'static>(delay: Duration, callback: F) -> TimerId {
+    add_timer(monotonic_ns() + delay.as_nanos() as u64, callback)
+}
+
+/// runs `callback` every `period`, starting one period from now, until cancelled
+pub fn add_periodic_timer<F: FnMut() + Send + 'static>(period: Duration, callback: F) -> TimerId {
+    let period = (period.as_nanos() as u64).max(1);
+    arm(monotonic_ns() + period, Some(period), Box::new(callback))
+}
+
+/// disarms a timer. returns false if it already fired (or was never there).
+/// a periodic timer that is running right now won't be re-armed afterwards
+pub fn cancel_timer(id: TimerId) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        if timers.remove(id).is_some() {
+            return true;
+        }
+        if timers.running == Some(id) && !timers.running_cancelled {
+            timers.running_cancelled = true;
+            return true;
+        }
+        false
+    })
+}
+
+/// called from the timer interrupt, raises the timer softirq if a timer is due. only the boot cpu
+/// ticks, so timers only ever run there
+pub fn check_expired() {
+    if monotonic_ns() >= NEXT_DEADLINE.load(Ordering::SeqCst) {
+        softirq::raise(SoftIrq::Timer);
+    }
+}
+
+/// runs every timer that's due, from the timer softirq
+pub(crate) fn run_expired_timers() {
+    loop {
+        let now = monotonic_ns();
+        let expired = interrupts::without_interrupts(|| TIMERS.lock().pop_expired(now));
+        let (id, deadline, mut timer) = match expired {
+            Some(expired) => expired,
+            None => break,
+        };
+        // the lock is dropped, so callbacks are free to add and cancel timers
+        (timer.callback)();
+        if let Some(period) = timer.period {
+            // stay in step with the original deadline, but skip whole periods we missed
+            // instead of firing a burst to catch up
+            let mut next = deadline + period;
+            let after = monotonic_ns();
+            if next <= after {
+                next = after + period - (after - next) % period;
+            }
+            interrupts::without_interrupts(|| {
+                let mut timers = TIMERS.lock();
+                timers.running = None;
+                if !timers.running_cancelled {
+                    timers.insert(id, next, timer);
+                }
+            });
+        }
+    }
+}